@@ -58,4 +58,5 @@ layout_primitive![
     f64,
     char,
     bool,
+    String,
 ];
\ No newline at end of file
@@ -0,0 +1,331 @@
+//! The header every message between a plugin and the host starts with.
+//!
+//! Calls in both directions are sealed in an envelope, as are the states
+//! handed to and from a plugin when it is reloaded, and so are their replies.
+//!
+//! The header has a fixed layout of little endian fields so it can be read
+//! regardless of how the payload after it is encoded:
+//!
+//! | bytes  | field                                  |
+//! |--------|----------------------------------------|
+//! | 0..4   | protocol version, [`PROTOCOL_VERSION`] |
+//! | 4..6   | [`MessageKind`]                        |
+//! | 6..8   | flags, none are defined yet            |
+//! | 8..12  | call id, echoed by the reply           |
+//! | 12..16 | payload length in bytes                |
+//!
+//! [`PROTOCOL_VERSION`]: crate::PROTOCOL_VERSION
+
+use std::{
+    convert::TryInto,
+    error::Error,
+    fmt,
+    io::{self, Write},
+};
+
+use crate::PROTOCOL_VERSION;
+
+pub const HEADER_LEN: usize = 16;
+/// Offset of the payload length in the header, for filling it in once a
+/// payload written after the header is done.
+pub const LEN_OFFSET: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    /// A call of an rpc, the payload is the rpc followed by its arguments.
+    Call,
+    /// The result of a call.
+    Reply,
+}
+
+impl MessageKind {
+    fn to_u16(self) -> u16 {
+        // Zero is left out so an empty header is not a valid message.
+        match self {
+            MessageKind::Call => 1,
+            MessageKind::Reply => 2,
+        }
+    }
+
+    fn from_u16(kind: u16) -> Option<Self> {
+        match kind {
+            1 => Some(MessageKind::Call),
+            2 => Some(MessageKind::Reply),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub version: u32,
+    pub kind: MessageKind,
+    /// Reserved for changes to the protocol, receivers reject flags they do
+    /// not know.
+    pub flags: u16,
+    pub call_id: u32,
+    pub len: u32,
+}
+
+impl Header {
+    /// The header of a message of the current protocol version.
+    pub fn new(kind: MessageKind, call_id: u32, len: u32) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            kind,
+            flags: 0,
+            call_id,
+            len,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0..4].copy_from_slice(&self.version.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.kind.to_u16().to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.call_id.to_le_bytes());
+        bytes[LEN_OFFSET..HEADER_LEN].copy_from_slice(&self.len.to_le_bytes());
+        bytes
+    }
+}
+
+/// Error returned when a message does not have a valid envelope.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvelopeError {
+    /// The message is shorter than a header.
+    Truncated(usize),
+    UnsupportedVersion(u32),
+    UnknownKind(u16),
+    UnexpectedKind {
+        expected: MessageKind,
+        found: MessageKind,
+    },
+    UnknownFlags(u16),
+    /// The payload length does not match the one in the header.
+    LengthMismatch {
+        expected: u32,
+        found: usize,
+    },
+    /// The reply is to another call than the one waited on.
+    CallIdMismatch {
+        expected: u32,
+        found: u32,
+    },
+}
+
+impl fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvelopeError::Truncated(len) => {
+                write!(f, "message of {} bytes is shorter than its header", len)
+            }
+            EnvelopeError::UnsupportedVersion(version) => write!(
+                f,
+                "protocol version {} is not supported, expected {}",
+                version, PROTOCOL_VERSION
+            ),
+            EnvelopeError::UnknownKind(kind) => write!(f, "unknown message kind {}", kind),
+            EnvelopeError::UnexpectedKind { expected, found } => {
+                write!(
+                    f,
+                    "expected a {:?} message but found a {:?}",
+                    expected, found
+                )
+            }
+            EnvelopeError::UnknownFlags(flags) => write!(f, "unknown message flags {:#x}", flags),
+            EnvelopeError::LengthMismatch { expected, found } => write!(
+                f,
+                "header announces {} bytes of payload but found {}",
+                expected, found
+            ),
+            EnvelopeError::CallIdMismatch { expected, found } => write!(
+                f,
+                "got the reply to call {} while waiting on call {}",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl Error for EnvelopeError {}
+
+/// Writes `payload` wrapped in an envelope of `kind` to `out`.
+pub fn seal(
+    out: &mut impl Write,
+    kind: MessageKind,
+    call_id: u32,
+    payload: &[u8],
+) -> io::Result<()> {
+    // Buffers live in 32 bit linear memory, so the length fits.
+    out.write_all(&Header::new(kind, call_id, payload.len() as u32).encode())?;
+    out.write_all(payload)
+}
+
+/// Checks the envelope of `message`, which should be of `kind`, returning its
+/// header and payload.
+pub fn open(message: &[u8], kind: MessageKind) -> Result<(Header, &[u8]), EnvelopeError> {
+    if message.len() < HEADER_LEN {
+        return Err(EnvelopeError::Truncated(message.len()));
+    }
+    let (header, payload) = message.split_at(HEADER_LEN);
+
+    let version = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    if version != PROTOCOL_VERSION {
+        return Err(EnvelopeError::UnsupportedVersion(version));
+    }
+    let found = u16::from_le_bytes(header[4..6].try_into().unwrap());
+    let found = MessageKind::from_u16(found).ok_or(EnvelopeError::UnknownKind(found))?;
+    if found != kind {
+        return Err(EnvelopeError::UnexpectedKind {
+            expected: kind,
+            found,
+        });
+    }
+    let flags = u16::from_le_bytes(header[6..8].try_into().unwrap());
+    if flags != 0 {
+        return Err(EnvelopeError::UnknownFlags(flags));
+    }
+    let len = u32::from_le_bytes(header[LEN_OFFSET..HEADER_LEN].try_into().unwrap());
+    if payload.len() != len as usize {
+        return Err(EnvelopeError::LengthMismatch {
+            expected: len,
+            found: payload.len(),
+        });
+    }
+
+    let header = Header {
+        version,
+        kind,
+        flags,
+        call_id: u32::from_le_bytes(header[8..12].try_into().unwrap()),
+        len,
+    };
+    Ok((header, payload))
+}
+
+/// Checks that `message` is the reply to the call `call_id`, returning its
+/// payload.
+pub fn open_reply(message: &[u8], call_id: u32) -> Result<&[u8], EnvelopeError> {
+    let (header, payload) = open(message, MessageKind::Reply)?;
+    if header.call_id != call_id {
+        return Err(EnvelopeError::CallIdMismatch {
+            expected: call_id,
+            found: header.call_id,
+        });
+    }
+    Ok(payload)
+}
+
+/// The call id of `message` without checking the rest of its envelope, so
+/// a reply to an invalid message can still be matched to its call.
+pub fn call_id(message: &[u8]) -> Option<u32> {
+    let bytes = message.get(8..12)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sealed(kind: MessageKind, call_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut message = Vec::new();
+        seal(&mut message, kind, call_id, payload).unwrap();
+        message
+    }
+
+    #[test]
+    fn round_trip() {
+        let message = sealed(MessageKind::Call, 7, b"payload");
+        assert_eq!(message.len(), HEADER_LEN + 7);
+
+        let (header, payload) = open(&message, MessageKind::Call).unwrap();
+        assert_eq!(header, Header::new(MessageKind::Call, 7, 7));
+        assert_eq!(payload, b"payload");
+        assert_eq!(call_id(&message), Some(7));
+    }
+
+    #[test]
+    fn reply_to_call() {
+        let message = sealed(MessageKind::Reply, 3, b"result");
+        assert_eq!(open_reply(&message, 3), Ok(&b"result"[..]));
+        assert_eq!(
+            open_reply(&message, 4),
+            Err(EnvelopeError::CallIdMismatch {
+                expected: 4,
+                found: 3
+            })
+        );
+    }
+
+    #[test]
+    fn truncated_header() {
+        let message = sealed(MessageKind::Call, 1, b"");
+        assert_eq!(
+            open(&message[..HEADER_LEN - 1], MessageKind::Call),
+            Err(EnvelopeError::Truncated(HEADER_LEN - 1))
+        );
+        assert_eq!(call_id(&message[..4]), None);
+    }
+
+    #[test]
+    fn wrong_version() {
+        let mut message = sealed(MessageKind::Call, 1, b"");
+        message[0..4].copy_from_slice(&(PROTOCOL_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            open(&message, MessageKind::Call),
+            Err(EnvelopeError::UnsupportedVersion(PROTOCOL_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn wrong_kind() {
+        let message = sealed(MessageKind::Reply, 1, b"");
+        assert_eq!(
+            open(&message, MessageKind::Call),
+            Err(EnvelopeError::UnexpectedKind {
+                expected: MessageKind::Call,
+                found: MessageKind::Reply
+            })
+        );
+
+        let mut message = message;
+        message[4..6].copy_from_slice(&0u16.to_le_bytes());
+        assert_eq!(
+            open(&message, MessageKind::Call),
+            Err(EnvelopeError::UnknownKind(0))
+        );
+    }
+
+    #[test]
+    fn unknown_flags() {
+        let mut message = sealed(MessageKind::Call, 1, b"");
+        message[6..8].copy_from_slice(&4u16.to_le_bytes());
+        assert_eq!(
+            open(&message, MessageKind::Call),
+            Err(EnvelopeError::UnknownFlags(4))
+        );
+    }
+
+    #[test]
+    fn payload_length_mismatch() {
+        let mut message = sealed(MessageKind::Call, 1, b"payload");
+        message.pop();
+        assert_eq!(
+            open(&message, MessageKind::Call),
+            Err(EnvelopeError::LengthMismatch {
+                expected: 7,
+                found: 6
+            })
+        );
+
+        message.extend_from_slice(b"ab");
+        assert_eq!(
+            open(&message, MessageKind::Call),
+            Err(EnvelopeError::LengthMismatch {
+                expected: 7,
+                found: 8
+            })
+        );
+    }
+}
@@ -1,8 +1,13 @@
 pub mod ecs;
+pub mod envelope;
+pub mod manifest;
+pub mod rpc;
 
 use std::{
     alloc,
     any::Any,
+    cell::RefCell,
+    collections::{HashMap, TryReserveError},
     io::Write,
     io::{self, Read},
     mem,
@@ -11,76 +16,254 @@ use std::{
 };
 
 use anyhow::{Result, anyhow};
-use ecs::{Component, Fetch, WorldQuery};
+use ecs::{Component, IntoTypeLayout, WorldQuery};
+use envelope::{Header, MessageKind, HEADER_LEN, LEN_OFFSET};
 use io::IoSlice;
 use mem::ManuallyDrop;
+use rpc::{RpcError, RpcId, RpcSignature, RpcTarget};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
+/// Version of the protocol between plugins and the host, the host refuses
+/// plugins built against a different version.
+// Keep `__protocol_version!` in sync.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+type Hook = Box<dyn FnMut(&mut Plugin) -> Result<()>>;
+type SaveHook = Box<dyn FnMut(&mut Plugin) -> Result<Vec<u8>>>;
+type LoadHook = Box<dyn FnMut(&mut Plugin, &[u8]) -> Result<()>>;
+/// Handles a call in the buffer, replacing it with the result.
+type Rpc = Box<dyn Fn(&mut Buffer)>;
+
+/// Builds the plugin declared by [`manifest!`], which also names it.
+#[derive(Default)]
 pub struct PluginBuilder {
-    name: String,
-    rpcs: Vec<Box<dyn Fn(&mut Vec<u8>)>>,
+    rpcs: HashMap<String, (RpcSignature, Rpc)>,
+    /// Rpcs the plugin calls, resolved to ids during init.
+    imports: Vec<String>,
+    hooks: Hooks,
+}
+
+#[derive(Default)]
+struct Hooks {
+    enable: Option<Hook>,
+    disable: Option<Hook>,
+    tick: Option<Hook>,
+    save: Option<SaveHook>,
+    load: Option<LoadHook>,
+}
+
+/// The initialized plugin, driven by the host through the lifecycle exports.
+struct Instance {
+    plugin: Plugin,
+    hooks: Hooks,
+}
+
+thread_local! {
+    static INSTANCE: RefCell<Option<Instance>> = RefCell::new(None);
+    // Kept apart from `INSTANCE` so rpcs can be called while a hook runs.
+    static RPCS: RefCell<HashMap<String, Rpc>> = RefCell::new(HashMap::new());
 }
 
 impl PluginBuilder {
-    pub fn new(name: &str) -> Self {
-        Self {
-            name: name.to_owned(),
-            rpcs: Vec::new(),
-        }
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `hook` once the host enabled the plugin after loading it.
+    pub fn on_enable<F: FnMut(&mut Plugin) -> Result<()> + 'static>(mut self, hook: F) -> Self {
+        self.hooks.enable = Some(Box::new(hook));
+        self
+    }
+
+    /// Runs `hook` before the host unloads or reloads the plugin.
+    pub fn on_disable<F: FnMut(&mut Plugin) -> Result<()> + 'static>(mut self, hook: F) -> Self {
+        self.hooks.disable = Some(Box::new(hook));
+        self
+    }
+
+    /// Runs `hook` every server tick while the plugin is enabled.
+    pub fn on_tick<F: FnMut(&mut Plugin) -> Result<()> + 'static>(mut self, hook: F) -> Self {
+        self.hooks.tick = Some(Box::new(hook));
+        self
     }
 
-    pub fn add_rpc<Args: DeserializeOwned, F: Fn(Args) + 'static>(mut self, rpc: F) -> Self {
-        // TODO: write rpc layout to buffer
-        self.rpcs.push(Box::new(move |buffer: &mut Vec<u8>| {
-            // let args = bincode::deserialize_from(buffer.as_ref()).unwrap();
-            // rpc(args);
-        }));
+    /// Runs `hook` before the plugin is reloaded, the state it returns is
+    /// passed to the `on_load` hook of the new instance.
+    pub fn on_save<F: FnMut(&mut Plugin) -> Result<Vec<u8>> + 'static>(mut self, hook: F) -> Self {
+        self.hooks.save = Some(Box::new(hook));
         self
     }
 
+    /// Runs `hook` with the state saved by the previous instance of the
+    /// plugin, after `init` and before `on_enable`.
+    pub fn on_load<F: FnMut(&mut Plugin, &[u8]) -> Result<()> + 'static>(
+        mut self,
+        hook: F,
+    ) -> Self {
+        self.hooks.load = Some(Box::new(hook));
+        self
+    }
 
+    /// Declares that the plugin calls the rpc `name`, which is then resolved
+    /// to an id during init together with the other imports instead of
+    /// after its first call.
+    pub fn import_rpc(mut self, name: &str) -> Self {
+        self.imports.push(name.to_owned());
+        self
+    }
+
+    /// Registers `rpc` under `name` for the host and other plugins to call,
+    /// replacing an earlier rpc of the same name.
+    pub fn add_rpc<Args, R, F>(mut self, name: &str, rpc: F) -> Self
+    where
+        Args: DeserializeOwned + IntoTypeLayout,
+        R: Serialize + IntoTypeLayout,
+        F: Fn(Args) -> R + 'static,
+    {
+        let signature = RpcSignature {
+            name: name.to_owned(),
+            args: Args::layout(),
+            result: R::layout(),
+        };
+        let rpc = move |buffer: &mut Buffer| {
+            let result = bincode::deserialize(buffer.payload())
+                .map(|(_, args): (String, Args)| rpc(args))
+                .map_err(|error| RpcError::Decode(error.to_string()));
+            write_reply(buffer, &result);
+        };
+        self.rpcs.insert(name.to_owned(), (signature, Box::new(rpc)));
+        self
+    }
+
+    /// Initializes the plugin, which is then driven by the host through the
+    /// hooks.
+    pub fn init(self) -> Result<()> {
+        if INSTANCE.with(|instance| instance.borrow().is_some()) {
+            return Err(anyhow!("plugin is already initialized"));
+        }
 
-    pub fn init(self) -> Result<Plugin> {
         let mut plugin = Plugin {
             buffer: Some(Box::new(Buffer::with_capacity(100_000))),
+            rpc_ids: HashMap::new(),
+            resolve_limit_reached: false,
+            next_call_id: 0,
         };
 
-        <(&u32, &mut u32) as Fetch>::access();
+        let (signatures, rpcs): (Vec<_>, HashMap<_, _>) = self
+            .rpcs
+            .into_iter()
+            .map(|(name, (signature, rpc))| (signature, (name, rpc)))
+            .unzip();
+        // Only called once, so it is not worth resolving.
+        let _: () = plugin.call(&RpcTarget::Name("rpc_register".to_owned()), &signatures)?;
+        RPCS.with(|registered| registered.replace(rpcs));
+        if !self.imports.is_empty() {
+            plugin.resolve_rpcs(&self.imports)?;
+        }
 
-        let _: () = plugin.call_rpc("world_query", &<(&u32, &mut u64, &f32, &u32) as Fetch>::access())?;
-        
-        Ok(plugin)
+        let hooks = self.hooks;
+        INSTANCE.with(|instance| instance.replace(Some(Instance { plugin, hooks })));
+        Ok(())
     }
 }
 
 pub struct Plugin {
     buffer: Option<Box<Buffer>>,
+    /// Ids of the rpcs resolved so far by name.
+    rpc_ids: HashMap<String, RpcId>,
+    /// Set once the host refused to resolve more rpcs, after which the rpcs
+    /// that are not resolved are called by name.
+    resolve_limit_reached: bool,
+    /// Id of the last call, which its reply has to echo.
+    next_call_id: u32,
 }
 
 impl Plugin {
+    /// Calls the rpc `name` of the host, or of another plugin if it is named
+    /// `plugin::rpc`. The first call sends the name, after which the rpc is
+    /// resolved to an id that later calls send instead.
     pub fn call_rpc<Args: Serialize + DeserializeOwned, R: Serialize + DeserializeOwned>(
         &mut self,
         name: &str,
         args: &Args,
     ) -> Result<R> {
-        let mut buffer = self.buffer.take().ok_or(anyhow!("buffer not avialable."))?;
-        buffer.clear();
+        if let Some(id) = self.rpc_ids.get(name).copied() {
+            return self.call(&RpcTarget::Id(id), args);
+        }
 
-        bincode::serialize_into(&mut buffer, name)?;
-        bincode::serialize_into(&mut buffer, args)?;
+        let result = self.call(&RpcTarget::Name(name.to_owned()), args);
+        // Rpcs that do not exist are not resolved, so a misspelled name does
+        // not use up an id.
+        let missing = match &result {
+            Err(error) => matches!(
+                error.downcast_ref::<RpcError>(),
+                Some(RpcError::UnknownRpc(_)) | Some(RpcError::UnknownPlugin(_))
+            ),
+            Ok(_) => false,
+        };
+        if !missing && !self.resolve_limit_reached {
+            if let Err(error) = self.resolve_rpcs(&[name.to_owned()]) {
+                match error.downcast_ref::<RpcError>() {
+                    Some(RpcError::ResolveLimit(_)) => self.resolve_limit_reached = true,
+                    _ => return Err(error),
+                }
+            }
+        }
+        result
+    }
+
+    /// Calls the rpc resolved to `id`, see [`Plugin::resolve_rpcs`].
+    pub fn call_rpc_id<Args: Serialize + DeserializeOwned, R: Serialize + DeserializeOwned>(
+        &mut self,
+        id: RpcId,
+        args: &Args,
+    ) -> Result<R> {
+        self.call(&RpcTarget::Id(id), args)
+    }
+
+    /// Resolves the rpcs `names` to ids with a single call to the host, after
+    /// which they are called by id. Names that do not exist yet still get an
+    /// id, calling it fails until the rpc exists.
+    pub fn resolve_rpcs(&mut self, names: &[String]) -> Result<Vec<RpcId>> {
+        let ids: Vec<RpcId> = self.call(&RpcTarget::Name("rpc_resolve".to_owned()), &names)?;
+        self.rpc_ids
+            .extend(names.iter().cloned().zip(ids.iter().copied()));
+        Ok(ids)
+    }
+
+    fn call<Args: Serialize, R: DeserializeOwned>(
+        &mut self,
+        target: &RpcTarget,
+        args: &Args,
+    ) -> Result<R> {
+        self.next_call_id = self.next_call_id.wrapping_add(1);
+        let call_id = self.next_call_id;
+
+        let mut buffer = self.buffer.take().ok_or(anyhow!("buffer not avialable."))?;
+        buffer.begin(MessageKind::Call, call_id);
+        let written = bincode::serialize_into(&mut *buffer, &(target, args));
+        if let Err(error) = written {
+            self.buffer.replace(buffer);
+            return Err(error.into());
+        }
+        buffer.seal();
 
         let buffer_ptr = Box::into_raw(buffer);
         unsafe { __quill_host_call(buffer_ptr) };
         let buffer = unsafe { Box::from_raw(buffer_ptr) };
 
-        let result = bincode::deserialize_from(buffer.as_slice())?;
-
+        let result = read_reply(buffer.as_slice(), call_id);
         self.buffer.replace(buffer);
-
-        Ok(result)
+        Ok(result??)
     }
 }
 
+/// Decodes the reply to the call `call_id` in `message`.
+fn read_reply<R: DeserializeOwned>(message: &[u8], call_id: u32) -> Result<Result<R, RpcError>> {
+    let payload = envelope::open_reply(message, call_id)?;
+    Ok(bincode::deserialize(payload)?)
+}
+
 #[repr(C)]
 #[derive(Debug)]
 struct Buffer {
@@ -106,10 +289,11 @@ impl Buffer {
         self.len = 0;
     }
 
-    fn reserve(&mut self, additional: usize) {
+    fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
         let mut raw = unsafe { Vec::from_raw_parts(self.ptr, self.len, self.cap) };
-        raw.reserve(additional);
+        let result = raw.try_reserve(additional);
         self.update_from_vec(raw);
+        result
     }
 
     fn update_from_vec(&mut self, vec: Vec<u8>) {
@@ -128,6 +312,26 @@ impl Buffer {
     fn as_slice(&self) -> &[u8] {
         self
     }
+
+    /// Starts a message of `kind` in the buffer, replacing its contents. Its
+    /// payload is written after it and [`Buffer::seal`] finishes it.
+    fn begin(&mut self, kind: MessageKind, call_id: u32) {
+        self.clear();
+        self.extend_from_slice(&Header::new(kind, call_id, 0).encode());
+    }
+
+    /// Fills in the payload length of the message started by
+    /// [`Buffer::begin`].
+    fn seal(&mut self) {
+        let len = (self.len - HEADER_LEN) as u32;
+        let header = unsafe { slice::from_raw_parts_mut(self.ptr, HEADER_LEN) };
+        header[LEN_OFFSET..].copy_from_slice(&len.to_le_bytes());
+    }
+
+    /// The payload of the message in the buffer, which is read in place.
+    fn payload(&self) -> &[u8] {
+        self.get(HEADER_LEN..).unwrap_or_default()
+    }
 }
 
 impl Drop for Buffer {
@@ -144,9 +348,12 @@ impl From<Vec<u8>> for Buffer {
     }
 }
 
+// Reserving up front turns running out of memory into an error instead of
+// an abort.
 impl Write for Buffer {
     #[inline]
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.try_reserve(buf.len()).map_err(io_error)?;
         self.extend_from_slice(buf);
         Ok(buf.len())
     }
@@ -154,7 +361,7 @@ impl Write for Buffer {
     #[inline]
     fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
         let len = bufs.iter().map(|b| b.len()).sum();
-        self.reserve(len);
+        self.try_reserve(len).map_err(io_error)?;
         for buf in bufs {
             self.extend_from_slice(buf);
         }
@@ -163,6 +370,7 @@ impl Write for Buffer {
 
     #[inline]
     fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.try_reserve(buf.len()).map_err(io_error)?;
         self.extend_from_slice(buf);
         Ok(())
     }
@@ -173,6 +381,10 @@ impl Write for Buffer {
     }
 }
 
+fn io_error(error: TryReserveError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, error)
+}
+
 impl Deref for Buffer {
     type Target = [u8];
     fn deref(&self) -> &Self::Target {
@@ -190,12 +402,131 @@ extern "C" {
     fn __quill_host_call(buffer: *mut Buffer);
 }
 
+/// Handles a call from the host, replacing the call in `buffer` with its
+/// result.
+#[no_mangle]
+extern "C" fn __quill_client_call(buffer: *mut Buffer) {
+    let buffer = unsafe { &mut *buffer };
+    let name = envelope::open(buffer.as_slice(), MessageKind::Call)
+        .map_err(|error| RpcError::Decode(error.to_string()))
+        .and_then(|(_, payload)| {
+            bincode::deserialize::<String>(payload)
+                .map_err(|error| RpcError::Decode(error.to_string()))
+        });
+    let handled = name.and_then(|name| {
+        RPCS.with(|rpcs| match rpcs.borrow().get(&name) {
+            Some(rpc) => {
+                rpc(buffer);
+                Ok(())
+            }
+            None => Err(RpcError::UnknownRpc(name)),
+        })
+    });
+    if let Err(error) = handled {
+        write_reply(buffer, &Err::<(), _>(error));
+    }
+}
+
+/// Replaces the call in `buffer` with the reply `reply`.
+fn write_reply<T: Serialize>(buffer: &mut Buffer, reply: &T) {
+    // Echoed even if the rest of the call's envelope is invalid.
+    let call_id = envelope::call_id(buffer.as_slice()).unwrap_or_default();
+    buffer.begin(MessageKind::Reply, call_id);
+    bincode::serialize_into(&mut *buffer, reply).expect("could not encode reply");
+    buffer.seal();
+}
+
+/// Allocates a buffer the host makes calls into the plugin with.
+#[no_mangle]
+extern "C" fn __quill_buffer_new() -> *mut Buffer {
+    Box::into_raw(Box::new(Buffer::from(Vec::new())))
+}
+
+#[no_mangle]
+extern "C" fn __quill_on_enable() {
+    run_hook("on_enable", |hooks| &mut hooks.enable);
+}
+
 #[no_mangle]
-extern "C" fn __quill_client_call() {}
+extern "C" fn __quill_on_disable() {
+    run_hook("on_disable", |hooks| &mut hooks.disable);
+}
 
 #[no_mangle]
-extern "C" fn __quill_buffer_reserve(buffer: *mut Buffer, additional: usize) {
+extern "C" fn __quill_on_tick() {
+    run_hook("on_tick", |hooks| &mut hooks.tick);
+}
+
+/// Runs a lifecycle hook if the plugin is initialized, panicking on failure
+/// so the host sees the call trap.
+fn run_hook(name: &str, hook: impl FnOnce(&mut Hooks) -> &mut Option<Hook>) {
+    INSTANCE.with(|instance| {
+        if let Some(Instance { plugin, hooks }) = instance.borrow_mut().as_mut() {
+            if let Some(hook) = hook(hooks) {
+                if let Err(error) = hook(plugin) {
+                    panic!("{} failed: {:?}", name, error);
+                }
+            }
+        }
+    });
+}
+
+/// Replaces the contents of `buffer` with the encoded state returned by the
+/// `on_save` hook, `None` without one.
+#[no_mangle]
+extern "C" fn __quill_save_state(buffer: *mut Buffer) {
+    let buffer = unsafe { &mut *buffer };
+    if let Err(error) = envelope::open(buffer.as_slice(), MessageKind::Call) {
+        panic!("invalid message envelope: {}", error);
+    }
+    let state = INSTANCE.with(|instance| match instance.borrow_mut().as_mut() {
+        Some(Instance {
+            plugin,
+            hooks: Hooks {
+                save: Some(save), ..
+            },
+        }) => match save(plugin) {
+            Ok(state) => Some(state),
+            Err(error) => panic!("on_save failed: {:?}", error),
+        },
+        _ => None,
+    });
+    write_reply(buffer, &state);
+}
+
+/// Passes the state in `buffer` to the `on_load` hook.
+#[no_mangle]
+extern "C" fn __quill_load_state(buffer: *mut Buffer) {
+    let buffer = unsafe { &mut *buffer };
+    let state = match envelope::open(buffer.as_slice(), MessageKind::Call) {
+        Ok((_, state)) => state,
+        Err(error) => panic!("invalid message envelope: {}", error),
+    };
+    INSTANCE.with(|instance| {
+        if let Some(Instance {
+            plugin,
+            hooks: Hooks {
+                load: Some(load), ..
+            },
+        }) = instance.borrow_mut().as_mut()
+        {
+            if let Err(error) = load(plugin, state) {
+                panic!("on_load failed: {:?}", error);
+            }
+        }
+    });
+    write_reply(buffer, &());
+}
+
+/// Grows `buffer` by at least `additional` bytes, returning a non-zero status
+/// if the memory could not be allocated.
+#[no_mangle]
+extern "C" fn __quill_buffer_reserve(buffer: *mut Buffer, additional: usize) -> u32 {
     let mut buffer = unsafe { Box::from_raw(buffer) };
-    buffer.reserve(additional);
+    let result = buffer.try_reserve(additional);
     Box::leak(buffer);
+    match result {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
 }
@@ -0,0 +1,78 @@
+//! Plugin manifests, embedded in the plugin as a custom wasm section so the
+//! host can read them before instantiating the plugin.
+//!
+//! ```ignore
+//! quill::manifest! {
+//!     name: "economy",
+//!     version: "0.1.0",
+//!     authors: ["Jane Doe <jane@example.com>"],
+//!     capabilities: ["world_spawn"],
+//! }
+//! ```
+//!
+//! The manifest is stored as TOML, with the quill protocol version the plugin
+//! was built against added as `quill`.
+//!
+//! Plugins that have to be loaded first are listed with a version requirement
+//! under `dependencies`, or `optional_dependencies` if the plugin works
+//! without them. Names containing `-` have to be quoted.
+//!
+//! ```ignore
+//! quill::manifest! {
+//!     name: "shop",
+//!     version: "0.2.0",
+//!     dependencies: { economy = "^0.1" },
+//!     optional_dependencies: { "chat-format" = ">=1.2, <2" },
+//! }
+//! ```
+
+/// Name of the custom section holding the manifest.
+pub const SECTION: &str = "quill_manifest";
+
+/// Embeds the manifest of the plugin, see the [module documentation](self).
+#[macro_export]
+macro_rules! manifest {
+    ($($key:ident : $value:tt),* $(,)?) => {
+        const __QUILL_MANIFEST_TOML: &str = concat!(
+            "quill = ",
+            $crate::__protocol_version!(),
+            "\n",
+            $(stringify!($key), " = ", stringify!($value), "\n",)*
+        );
+
+        // Must match `SECTION`.
+        #[link_section = "quill_manifest"]
+        #[used]
+        static __QUILL_MANIFEST: [u8; __QUILL_MANIFEST_TOML.len()] =
+            $crate::manifest::to_bytes(__QUILL_MANIFEST_TOML);
+    };
+}
+
+/// `PROTOCOL_VERSION` as a literal usable in `concat!`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __protocol_version {
+    () => {
+        3
+    };
+}
+
+#[doc(hidden)]
+pub const fn to_bytes<const N: usize>(text: &str) -> [u8; N] {
+    let text = text.as_bytes();
+    let mut bytes = [0; N];
+    let mut i = 0;
+    while i < N {
+        bytes[i] = text[i];
+        i += 1;
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn protocol_version_literal_matches() {
+        assert_eq!(crate::__protocol_version!(), crate::PROTOCOL_VERSION);
+    }
+}
@@ -0,0 +1,76 @@
+use std::{error::Error, fmt};
+
+use serde::{Deserialize, Serialize};
+
+use crate::ecs::TypeLayout;
+
+/// Error returned by the host when it could not handle an rpc.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RpcError {
+    UnknownRpc(String),
+    Decode(String),
+    InvalidArguments(String),
+    /// The plugin was not granted the capability the rpc requires.
+    PermissionDenied {
+        rpc: String,
+        capability: String,
+    },
+    /// The plugin of a `plugin::rpc` call is not enabled.
+    UnknownPlugin(String),
+    /// The plugin of a `plugin::rpc` call failed while handling it.
+    PluginFailed {
+        plugin: String,
+        message: String,
+    },
+    /// The id was not handed out by `rpc_resolve`.
+    UnknownRpcId(RpcId),
+    /// The plugin resolved as many rpcs as the host allows.
+    ResolveLimit(u32),
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::UnknownRpc(name) => write!(f, "no rpc named {}", name),
+            RpcError::Decode(message) => write!(f, "could not decode rpc call: {}", message),
+            RpcError::InvalidArguments(message) => write!(f, "invalid rpc arguments: {}", message),
+            RpcError::PermissionDenied { rpc, capability } => {
+                write!(f, "calling {} requires the {} capability", rpc, capability)
+            }
+            RpcError::UnknownPlugin(plugin) => write!(f, "no plugin named {} is enabled", plugin),
+            RpcError::PluginFailed { plugin, message } => {
+                write!(f, "plugin {} failed: {}", plugin, message)
+            }
+            RpcError::UnknownRpcId(id) => write!(f, "no rpc with id {}", id),
+            RpcError::ResolveLimit(limit) => write!(f, "cannot resolve more than {} rpcs", limit),
+        }
+    }
+}
+
+impl Error for RpcError {}
+
+/// Describes an rpc handled by a plugin, announced to the host during init.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RpcSignature {
+    pub name: String,
+    pub args: TypeLayout,
+    pub result: TypeLayout,
+}
+
+/// Id the host assigned to an rpc name through `rpc_resolve`, valid for the
+/// lifetime of the plugin instance that resolved it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RpcId(pub u32);
+
+impl fmt::Display for RpcId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// The rpc a plugin calls, written to the buffer ahead of the arguments.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RpcTarget {
+    Name(String),
+    Id(RpcId),
+}
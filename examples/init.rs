@@ -2,9 +2,18 @@ use std::vec;
 
 use quill::{PluginBuilder, ecs::Query};
 
+quill::manifest! {
+    name: "hello-world",
+    version: "0.1.0",
+}
+
 fn main() {
-    PluginBuilder::new("hello world")
-        .add_rpc(|name: String| println!("hello {}!", name))
+    PluginBuilder::new()
+        .add_rpc("greet", |name: String| format!("hello {}!", name))
+        .on_enable(|_| {
+            println!("hello world enabled");
+            Ok(())
+        })
         // .add_system(foo_system)
         .init()
         .expect("could not initlize plugin");
@@ -0,0 +1,65 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::plugin::{Backend, PluginConfig};
+
+/// Server settings, read from `server.toml`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub host: HostConfig,
+    /// Settings of plugins without a section in `plugins`.
+    pub plugin: PluginConfig,
+    /// Settings of individual plugins by name. Missing fields take the
+    /// built-in defaults rather than those of `plugin`.
+    pub plugins: HashMap<String, PluginConfig>,
+}
+
+/// Settings of the plugin host.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct HostConfig {
+    pub plugin_dir: PathBuf,
+    /// Where compiled plugins are cached, caching is disabled if unset.
+    pub cache_dir: Option<PathBuf>,
+    pub backend: Backend,
+    /// File trap reports of plugins are appended to.
+    pub trap_log: Option<PathBuf>,
+    /// Runs plugins in deterministic mode with the seed, see
+    /// `PluginManager::set_deterministic`.
+    pub deterministic_seed: Option<u64>,
+    /// Directory plugins store their data in, each in a directory named
+    /// after the plugin.
+    pub data_dir: PathBuf,
+}
+
+impl Default for HostConfig {
+    fn default() -> Self {
+        Self {
+            plugin_dir: PathBuf::from("./plugins"),
+            cache_dir: Some(PathBuf::from("./cache/plugins")),
+            backend: Backend::default(),
+            trap_log: None,
+            deterministic_seed: None,
+            data_dir: PathBuf::from("./data/plugins"),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config at `path`, falling back to the defaults if it does
+    /// not exist.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(config) => Ok(toml::from_str(&config)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
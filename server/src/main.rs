@@ -1,13 +1,108 @@
+mod config;
 mod plugin;
 
+use std::{
+    env,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use anyhow::Result;
+use bevy_ecs::World;
+use config::Config;
+use plugin::{LoadFailure, PluginManager, ProfileReport};
+
+/// Time between ticks, which is also how long plugin calls may take by
+/// default.
+pub(crate) const TICK: Duration = Duration::from_millis(50);
+/// Ticks between the profiles logged with `--profile`.
+const PROFILE_INTERVAL: u64 = 200;
+
 fn main() {
-    let result = plugin::Plugin::load("./target/wasm32-wasi/debug/examples/init.wasm");
-    match result {
-        Err(err) => println!("{:?}", err),
-        Ok(_) => println!("all good?"),
+    tracing_subscriber::fmt::init();
+
+    let watch = env::args().any(|arg| arg == "--watch");
+    let profile = env::args().any(|arg| arg == "--profile");
+
+    let config = match Config::load("./server.toml") {
+        Ok(config) => config,
+        Err(err) => {
+            tracing::error!("could not read server.toml: {:?}", err);
+            return;
+        }
+    };
+
+    let mut server = match Server::new(&config) {
+        Ok(server) => server,
+        Err(err) => {
+            tracing::error!("could not start server: {:?}", err);
+            return;
+        }
+    };
+    match server.plugins.load_dir(&config.host.plugin_dir) {
+        Err(err) => tracing::error!("could not read plugin directory: {:?}", err),
+        Ok(failures) => report(failures),
+    }
+
+    for tick in 1u64.. {
+        if watch {
+            report(server.plugins.reload_changed());
+        }
+        server.plugins.tick();
+        if profile && tick % PROFILE_INTERVAL == 0 {
+            tracing::info!(
+                "plugin profile of the last {} ticks:\n{}",
+                PROFILE_INTERVAL,
+                server.profile()
+            );
+            server.plugins.reset_profiles();
+        }
+        thread::sleep(TICK);
     }
 }
 
-struct Server { 
-    plugins: Vec<()>,
-}
\ No newline at end of file
+fn report(failures: Vec<LoadFailure>) {
+    for failure in failures {
+        tracing::error!(
+            "could not load {}: {:?}",
+            failure.path.display(),
+            failure.error
+        );
+    }
+}
+
+struct Server {
+    plugins: PluginManager,
+}
+
+impl Server {
+    fn new(config: &Config) -> Result<Self> {
+        let world = Arc::new(Mutex::new(World::default()));
+
+        let mut plugins = PluginManager::new(world);
+        plugins.set_backend(config.host.backend)?;
+        plugins.set_data_dir(&config.host.data_dir);
+        if let Some(cache_dir) = &config.host.cache_dir {
+            plugins.set_cache_dir(cache_dir);
+        }
+        if let Some(trap_log) = &config.host.trap_log {
+            plugins.set_trap_log(trap_log);
+        }
+        if let Some(seed) = config.host.deterministic_seed {
+            plugins.set_deterministic(seed, TICK);
+        }
+        plugins.set_default_config(config.plugin.clone());
+        for (name, plugin) in &config.plugins {
+            plugins.set_config(name, plugin.clone());
+        }
+
+        Ok(Self { plugins })
+    }
+
+    /// Call counts and timings of every plugin since the profiles were last
+    /// reset.
+    fn profile(&self) -> ProfileReport {
+        self.plugins.profile()
+    }
+}
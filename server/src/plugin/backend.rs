@@ -0,0 +1,65 @@
+use std::fmt;
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use wasmer::CompilerConfig;
+
+/// Compiler used to turn plugins into native code.
+///
+/// Only the backends enabled through the cargo features of the same name
+/// can be used. Cranelift and Singlepass compile quickly, LLVM produces the
+/// fastest code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Cranelift,
+    Singlepass,
+    Llvm,
+}
+
+impl Backend {
+    pub fn is_available(self) -> bool {
+        match self {
+            Backend::Cranelift => cfg!(feature = "cranelift"),
+            Backend::Singlepass => cfg!(feature = "singlepass"),
+            Backend::Llvm => cfg!(feature = "llvm"),
+        }
+    }
+
+    pub fn compiler(self) -> Result<Box<dyn CompilerConfig>> {
+        match self {
+            #[cfg(feature = "cranelift")]
+            Backend::Cranelift => Ok(Box::new(wasmer::Cranelift::default())),
+            #[cfg(feature = "singlepass")]
+            Backend::Singlepass => Ok(Box::new(wasmer::Singlepass::default())),
+            #[cfg(feature = "llvm")]
+            Backend::Llvm => Ok(Box::new(wasmer::LLVM::default())),
+            #[allow(unreachable_patterns)]
+            backend => bail!(
+                "the {} backend is not available, build with the {0} feature to enable it",
+                backend
+            ),
+        }
+    }
+}
+
+impl Default for Backend {
+    /// The fastest compiling backend that is available.
+    fn default() -> Self {
+        [Backend::Cranelift, Backend::Singlepass, Backend::Llvm]
+            .iter()
+            .copied()
+            .find(|backend| backend.is_available())
+            .unwrap_or(Backend::Cranelift)
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Backend::Cranelift => "cranelift",
+            Backend::Singlepass => "singlepass",
+            Backend::Llvm => "llvm",
+        })
+    }
+}
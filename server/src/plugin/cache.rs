@@ -0,0 +1,93 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::Result;
+use wasmer::{Module, Store};
+
+/// On-disk cache of compiled plugin modules.
+///
+/// Entries are keyed by a hash of the wasm bytes together with an identity
+/// describing everything else that affects the compiled artifact, so a
+/// rebuilt plugin or a different host compiles afresh.
+pub struct ModuleCache {
+    dir: PathBuf,
+}
+
+impl ModuleCache {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_owned(),
+        }
+    }
+
+    /// Loads the module of the plugin `name` from the cache, compiling and
+    /// caching it if no entry exists.
+    pub fn load(&self, store: &Store, name: &str, wasm: &[u8], identity: &str) -> Result<Module> {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(identity.as_bytes());
+        hasher.update(wasm);
+        let file_name = format!("{}-{}.bin", name, hasher.finalize().to_hex());
+        let path = self.dir.join(&file_name);
+
+        if let Ok(bytes) = fs::read(&path) {
+            // Safe as long as nobody but us writes to the cache directory.
+            if let Ok(module) = unsafe { Module::deserialize(store, &bytes) } {
+                return Ok(module);
+            }
+        }
+
+        let module = Module::new(store, wasm)?;
+        // Failing to write the cache only costs a compile next time.
+        if let Ok(bytes) = module.serialize() {
+            if self.store(&file_name, &bytes).is_ok() {
+                self.remove_stale(name, &file_name);
+            }
+        }
+        Ok(module)
+    }
+
+    /// Writes the entry `file_name`, which loads never see half written as it
+    /// is written to a temporary file first and then renamed into place.
+    fn store(&self, file_name: &str, bytes: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        // Unique per process so servers sharing the directory do not write to
+        // the same file.
+        let temp = self
+            .dir
+            .join(format!(".{}.{}.tmp", file_name, process::id()));
+        let result =
+            fs::write(&temp, bytes).and_then(|()| fs::rename(&temp, self.dir.join(file_name)));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        result
+    }
+
+    /// Removes entries of `name` other than `current`.
+    fn remove_stale(&self, name: &str, current: &str) {
+        let prefix = format!("{}-", name);
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let file_name = entry.file_name();
+            let file_name = match file_name.to_str() {
+                Some(file_name) => file_name,
+                None => continue,
+            };
+            let is_entry = file_name
+                .strip_prefix(&prefix)
+                .and_then(|rest| rest.strip_suffix(".bin"))
+                .map_or(false, |hash| {
+                    hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit())
+                });
+            if is_entry && file_name != current {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+}
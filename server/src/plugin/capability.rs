@@ -0,0 +1,23 @@
+use std::fmt;
+
+use serde::Deserialize;
+
+/// Permission to call a host rpc. Plugins request capabilities in their
+/// manifest and only get those the server config grants them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// Spawning entities through `world_spawn`.
+    WorldSpawn,
+    /// Querying components through `world_query`.
+    WorldQuery,
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Capability::WorldSpawn => f.write_str("world_spawn"),
+            Capability::WorldQuery => f.write_str("world_query"),
+        }
+    }
+}
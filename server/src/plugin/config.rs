@@ -0,0 +1,127 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use serde::Deserialize;
+
+use super::Capability;
+use crate::TICK;
+
+/// Settings applied to a single plugin.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PluginConfig {
+    pub fuel: FuelLimits,
+    /// Maximum size of the plugin's linear memory in 64 KiB pages.
+    pub max_memory_pages: u32,
+    /// Command line arguments passed to the plugin through WASI.
+    pub args: Vec<String>,
+    /// Environment variables passed to the plugin through WASI.
+    pub env: HashMap<String, String>,
+    /// Capabilities the plugin may use if its manifest requests them.
+    pub capabilities: HashSet<Capability>,
+    /// Milliseconds a call into the plugin besides `_start` may take before
+    /// it is interrupted, the tick length by default and never if 0.
+    pub timeout_ms: u64,
+    /// Number of traps after which the plugin is quarantined, counting calls
+    /// that ran out of fuel or failed before reaching the plugin.
+    pub max_traps: usize,
+}
+
+impl Default for PluginConfig {
+    fn default() -> Self {
+        Self {
+            fuel: FuelLimits::default(),
+            max_memory_pages: 1024,
+            args: Vec::new(),
+            env: HashMap::new(),
+            capabilities: HashSet::new(),
+            timeout_ms: TICK.as_millis() as u64,
+            max_traps: 3,
+        }
+    }
+}
+
+/// The ways the host enters guest code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CallKind {
+    /// The `_start` export run while loading the plugin.
+    Start,
+    Enable,
+    Disable,
+    Tick,
+    /// A call of one of the plugin's rpcs through [`Plugin::call`].
+    ///
+    /// [`Plugin::call`]: super::Plugin::call
+    Call,
+    /// Saving the plugin's state before it is reloaded.
+    Save,
+    /// Restoring the state saved by the previous instance of the plugin.
+    Load,
+}
+
+impl CallKind {
+    /// Name of the export the host calls.
+    pub fn export(&self) -> &'static str {
+        match self {
+            CallKind::Start => "_start",
+            CallKind::Enable => "__quill_on_enable",
+            CallKind::Disable => "__quill_on_disable",
+            CallKind::Tick => "__quill_on_tick",
+            CallKind::Call => "__quill_client_call",
+            CallKind::Save => "__quill_save_state",
+            CallKind::Load => "__quill_load_state",
+        }
+    }
+}
+
+impl fmt::Display for CallKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.export())
+    }
+}
+
+/// Number of instructions a plugin may execute per call into it.
+///
+/// Instructions executed while the guest waits on `__quill_host_call` count
+/// towards the budget of the call that is in flight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct FuelLimits {
+    pub start: u64,
+    pub enable: u64,
+    pub disable: u64,
+    pub tick: u64,
+    pub call: u64,
+    pub save: u64,
+    pub load: u64,
+}
+
+impl FuelLimits {
+    pub fn limit(&self, kind: CallKind) -> u64 {
+        match kind {
+            CallKind::Start => self.start,
+            CallKind::Enable => self.enable,
+            CallKind::Disable => self.disable,
+            CallKind::Tick => self.tick,
+            CallKind::Call => self.call,
+            CallKind::Save => self.save,
+            CallKind::Load => self.load,
+        }
+    }
+}
+
+impl Default for FuelLimits {
+    fn default() -> Self {
+        Self {
+            start: 1_000_000_000,
+            enable: 100_000_000,
+            disable: 100_000_000,
+            tick: 10_000_000,
+            call: 10_000_000,
+            save: 100_000_000,
+            load: 100_000_000,
+        }
+    }
+}
@@ -0,0 +1,403 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{anyhow, bail, Result};
+use semver::Version;
+
+use super::{LoadFailure, Manifest, PluginFile};
+
+/// Checks that the dependencies of `manifest` are in `versions`, the
+/// versions of the plugins that are or will be loaded, with a matching
+/// version.
+pub fn check_dependencies(manifest: &Manifest, versions: &HashMap<String, Version>) -> Result<()> {
+    for (name, req) in &manifest.dependencies {
+        match versions.get(name) {
+            Some(version) if !req.matches(version) => {
+                bail!("requires {} {} but found version {}", name, req, version)
+            }
+            Some(_) => {}
+            None => bail!("requires {} {} which is missing", name, req),
+        }
+    }
+    for (name, req) in &manifest.optional_dependencies {
+        match versions.get(name) {
+            Some(version) if !req.matches(version) => bail!(
+                "optionally requires {} {} but found version {}",
+                name,
+                req,
+                version
+            ),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Checks that replacing a loaded plugin with `manifest` keeps the
+/// requirements of the other plugins satisfied.
+pub fn check_dependents<'a>(
+    manifest: &Manifest,
+    others: impl IntoIterator<Item = &'a Manifest>,
+) -> Result<()> {
+    for other in others {
+        let req = other
+            .dependencies
+            .get(&manifest.name)
+            .or_else(|| other.optional_dependencies.get(&manifest.name));
+        match req {
+            Some(req) if !req.matches(&manifest.version) => bail!(
+                "{} requires {} {} but the new version is {}",
+                other.name,
+                manifest.name,
+                req,
+                manifest.version
+            ),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Checks that no plugin in `others` requires the plugin named `name`, so it
+/// can be unloaded. Plugins that only optionally depend on it keep running.
+pub fn check_unload<'a>(name: &str, others: impl IntoIterator<Item = &'a Manifest>) -> Result<()> {
+    let dependents: Vec<&str> = others
+        .into_iter()
+        .filter(|other| other.dependencies.contains_key(name))
+        .map(|other| other.name.as_str())
+        .collect();
+    if !dependents.is_empty() {
+        bail!(
+            "{} is required by {}, unload them first",
+            name,
+            dependents.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Orders `files` so every plugin comes after its dependencies, `loaded`
+/// being the versions of the plugins that are already loaded.
+///
+/// Plugins that have the name of another plugin, miss a dependency or are
+/// part of a dependency cycle are returned as failures instead, as are the
+/// plugins depending on them.
+pub fn load_order(
+    files: Vec<PluginFile>,
+    loaded: &HashMap<String, Version>,
+) -> (Vec<PluginFile>, Vec<LoadFailure>) {
+    let mut failures = Vec::new();
+    let mut pending = BTreeMap::new();
+    for file in files {
+        let name = file.manifest.name.clone();
+        if loaded.contains_key(&name) || pending.contains_key(&name) {
+            failures.push(LoadFailure {
+                path: file.path,
+                error: anyhow!("a plugin named {} is already loaded", name),
+            });
+        } else {
+            pending.insert(name, file);
+        }
+    }
+
+    // Removing a plugin can leave others without a dependency, so check
+    // until nothing changes.
+    loop {
+        let mut versions = loaded.clone();
+        versions.extend(
+            pending
+                .iter()
+                .map(|(name, file)| (name.clone(), file.manifest.version.clone())),
+        );
+        let missing: Vec<(String, anyhow::Error)> = pending
+            .iter()
+            .filter_map(|(name, file)| {
+                let error = check_dependencies(&file.manifest, &versions).err()?;
+                Some((name.clone(), error))
+            })
+            .collect();
+        if missing.is_empty() {
+            break;
+        }
+        for (name, error) in missing {
+            if let Some(file) = pending.remove(&name) {
+                failures.push(LoadFailure {
+                    path: file.path,
+                    error,
+                });
+            }
+        }
+    }
+
+    let mut order = Vec::new();
+    while !pending.is_empty() {
+        let ready: Vec<String> = pending
+            .iter()
+            .filter(|(_, file)| {
+                file.manifest
+                    .all_dependencies()
+                    .all(|(name, _)| !pending.contains_key(name))
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        if ready.is_empty() {
+            // Every plugin left waits on another one left. There might be
+            // several cycles, so each plugin is told about the one it is
+            // stuck behind.
+            let cycles: Vec<Vec<String>> = pending
+                .keys()
+                .map(|name| find_cycle(&pending, name))
+                .collect();
+            for ((name, file), cycle) in pending.into_iter().zip(cycles) {
+                let error = if cycle.contains(&name) {
+                    anyhow!("is part of the dependency cycle {}", cycle.join(" -> "))
+                } else {
+                    anyhow!("depends on the dependency cycle {}", cycle.join(" -> "))
+                };
+                failures.push(LoadFailure {
+                    path: file.path,
+                    error,
+                });
+            }
+            break;
+        }
+
+        for name in ready {
+            order.extend(pending.remove(&name));
+        }
+    }
+    (order, failures)
+}
+
+/// Follows dependencies in `pending`, where every plugin has one, from
+/// `start` until a plugin repeats.
+fn find_cycle(pending: &BTreeMap<String, PluginFile>, start: &str) -> Vec<String> {
+    let mut path: Vec<String> = Vec::new();
+    let mut next = Some(start.to_owned());
+    while let Some(name) = next {
+        if let Some(start) = path.iter().position(|visited| *visited == name) {
+            path.drain(..start);
+            path.push(name);
+            break;
+        }
+        next = pending[&name]
+            .manifest
+            .all_dependencies()
+            .map(|(dependency, _)| dependency)
+            .find(|dependency| pending.contains_key(*dependency))
+            .cloned();
+        path.push(name);
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::SystemTime};
+
+    use semver::VersionReq;
+
+    use super::*;
+
+    fn file(name: &str, version: &str, dependencies: &[(&str, &str)]) -> PluginFile {
+        optional_file(name, version, dependencies, &[])
+    }
+
+    fn optional_file(
+        name: &str,
+        version: &str,
+        dependencies: &[(&str, &str)],
+        optional_dependencies: &[(&str, &str)],
+    ) -> PluginFile {
+        PluginFile {
+            path: PathBuf::from(format!("{}.wasm", name)),
+            modified: SystemTime::UNIX_EPOCH,
+            wasm: Vec::new(),
+            manifest: Manifest {
+                name: name.to_owned(),
+                version: version.parse().unwrap(),
+                authors: Vec::new(),
+                quill: quill::PROTOCOL_VERSION,
+                capabilities: Vec::new(),
+                dependencies: requirements(dependencies),
+                optional_dependencies: requirements(optional_dependencies),
+            },
+        }
+    }
+
+    fn requirements(dependencies: &[(&str, &str)]) -> BTreeMap<String, VersionReq> {
+        dependencies
+            .iter()
+            .map(|(name, req)| (name.to_string(), req.parse().unwrap()))
+            .collect()
+    }
+
+    fn names(order: &[PluginFile]) -> Vec<&str> {
+        order
+            .iter()
+            .map(|file| file.manifest.name.as_str())
+            .collect()
+    }
+
+    /// The failures by file name with their error.
+    fn failures(failures: &[LoadFailure]) -> BTreeMap<String, String> {
+        failures
+            .iter()
+            .map(|failure| {
+                let name = failure.path.file_stem().unwrap().to_string_lossy();
+                (name.into_owned(), failure.error.to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dependencies_come_first() {
+        let files = vec![
+            file("a", "1.0.0", &[("b", "^1")]),
+            file("b", "1.2.0", &[("c", "^1")]),
+            file("c", "1.0.0", &[]),
+        ];
+        let (order, failed) = load_order(files, &HashMap::new());
+        assert_eq!(names(&order), ["c", "b", "a"]);
+        assert!(failed.is_empty());
+    }
+
+    #[test]
+    fn loaded_plugins_satisfy_dependencies() {
+        let loaded = vec![("b".to_owned(), "1.0.0".parse().unwrap())]
+            .into_iter()
+            .collect();
+        let (order, failed) = load_order(vec![file("a", "1.0.0", &[("b", "^1")])], &loaded);
+        assert_eq!(names(&order), ["a"]);
+        assert!(failed.is_empty());
+    }
+
+    #[test]
+    fn missing_dependency() {
+        let files = vec![file("a", "1.0.0", &[("b", "^1")]), file("c", "1.0.0", &[])];
+        let (order, failed) = load_order(files, &HashMap::new());
+        assert_eq!(names(&order), ["c"]);
+        let failed = failures(&failed);
+        assert_eq!(failed.len(), 1);
+        assert!(failed["a"].contains("missing"), "{}", failed["a"]);
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let files = vec![file("a", "1.0.0", &[("b", "^2")]), file("b", "1.4.0", &[])];
+        let (order, failed) = load_order(files, &HashMap::new());
+        assert_eq!(names(&order), ["b"]);
+        let failed = failures(&failed);
+        assert!(
+            failed["a"].contains("found version 1.4.0"),
+            "{}",
+            failed["a"]
+        );
+    }
+
+    #[test]
+    fn dependency_that_failed_earlier() {
+        // `b` misses its dependency, so `a` which requires `b` fails too.
+        let files = vec![
+            file("a", "1.0.0", &[("b", "^1")]),
+            file("b", "1.0.0", &[("missing", "^1")]),
+            file("c", "1.0.0", &[]),
+        ];
+        let (order, failed) = load_order(files, &HashMap::new());
+        assert_eq!(names(&order), ["c"]);
+        let failed = failures(&failed);
+        assert_eq!(failed.len(), 2);
+        assert!(failed["a"].contains("requires b"), "{}", failed["a"]);
+        assert!(failed["b"].contains("requires missing"), "{}", failed["b"]);
+    }
+
+    #[test]
+    fn duplicate_name() {
+        let loaded = vec![("a".to_owned(), "1.0.0".parse().unwrap())]
+            .into_iter()
+            .collect();
+        let (order, failed) = load_order(vec![file("a", "1.1.0", &[])], &loaded);
+        assert!(order.is_empty());
+        assert!(failures(&failed)["a"].contains("already loaded"));
+    }
+
+    #[test]
+    fn cycle() {
+        let files = vec![
+            file("a", "1.0.0", &[("b", "^1")]),
+            file("b", "1.0.0", &[("a", "^1")]),
+            file("c", "1.0.0", &[("a", "^1")]),
+            file("d", "1.0.0", &[]),
+        ];
+        let (order, failed) = load_order(files, &HashMap::new());
+        assert_eq!(names(&order), ["d"]);
+        let failed = failures(&failed);
+        assert!(failed["a"].contains("part of the dependency cycle a -> b -> a"));
+        assert!(failed["b"].contains("part of the dependency cycle b -> a -> b"));
+        assert!(failed["c"].contains("depends on the dependency cycle a -> b -> a"));
+    }
+
+    #[test]
+    fn separate_cycles() {
+        let files = vec![
+            file("a", "1.0.0", &[("b", "^1")]),
+            file("b", "1.0.0", &[("a", "^1")]),
+            file("c", "1.0.0", &[("d", "^1")]),
+            file("d", "1.0.0", &[("c", "^1")]),
+            file("e", "1.0.0", &[("c", "^1")]),
+        ];
+        let (order, failed) = load_order(files, &HashMap::new());
+        assert!(order.is_empty());
+        let failed = failures(&failed);
+        assert!(failed["a"].ends_with("part of the dependency cycle a -> b -> a"));
+        assert!(failed["b"].ends_with("part of the dependency cycle b -> a -> b"));
+        assert!(failed["c"].ends_with("part of the dependency cycle c -> d -> c"));
+        assert!(failed["d"].ends_with("part of the dependency cycle d -> c -> d"));
+        assert!(failed["e"].ends_with("depends on the dependency cycle c -> d -> c"));
+    }
+
+    #[test]
+    fn optional_dependencies() {
+        let files = vec![
+            optional_file("a", "1.0.0", &[], &[("b", "^1")]),
+            optional_file("c", "1.0.0", &[], &[("missing", "^1")]),
+            file("b", "1.0.0", &[]),
+        ];
+        let (order, failed) = load_order(files, &HashMap::new());
+        assert_eq!(names(&order), ["b", "c", "a"]);
+        assert!(failed.is_empty());
+    }
+
+    #[test]
+    fn optional_dependency_version_mismatch() {
+        let files = vec![
+            optional_file("a", "1.0.0", &[], &[("b", "^2")]),
+            file("b", "1.0.0", &[]),
+        ];
+        let (order, failed) = load_order(files, &HashMap::new());
+        assert_eq!(names(&order), ["b"]);
+        assert!(failures(&failed)["a"].contains("optionally requires b"));
+    }
+
+    #[test]
+    fn finds_cycle_behind_a_path() {
+        let pending: BTreeMap<String, PluginFile> = vec![
+            file("a", "1.0.0", &[("b", "^1")]),
+            file("b", "1.0.0", &[("c", "^1")]),
+            file("c", "1.0.0", &[("b", "^1")]),
+        ]
+        .into_iter()
+        .map(|file| (file.manifest.name.clone(), file))
+        .collect();
+        assert_eq!(find_cycle(&pending, "a"), ["b", "c", "b"]);
+    }
+
+    #[test]
+    fn required_plugins_are_not_unloaded() {
+        let a = file("a", "1.0.0", &[("b", "^1")]).manifest;
+        let c = optional_file("c", "1.0.0", &[], &[("b", "^1")]).manifest;
+        let error = check_unload("b", vec![&a, &c]).unwrap_err();
+        assert!(error.to_string().contains("required by a,"), "{}", error);
+        assert!(check_unload("b", vec![&c]).is_ok());
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use wasmer::{
+    Array, ExportError, Function, HostEnvInitError, ImportObject, Instance, LazyInit, Memory,
+    Store, WasmPtr, WasmerEnv,
+};
+
+use super::{lock, PluginError};
+
+/// The WASI versions plugins may import, with the size of the subscriptions
+/// passed to `poll_oneoff` in each.
+const WASI: [(&str, u32); 2] = [("wasi_snapshot_preview1", 48), ("wasi_unstable", 56)];
+
+/// Size of the events written by `poll_oneoff`, the same in both versions.
+const EVENT_LEN: u32 = 32;
+const EVENTTYPE_CLOCK: u8 = 0;
+
+const ESUCCESS: u32 = 0;
+const EFAULT: u32 = 21;
+const EINVAL: u32 = 28;
+const ENOTSUP: u16 = 58;
+
+/// Time and randomness for plugins in deterministic mode. Time only advances
+/// with the server's ticks and randomness is derived from a seed.
+pub struct Deterministic {
+    seed: u64,
+    tick: Duration,
+    ticks: AtomicU64,
+}
+
+impl Deterministic {
+    pub fn new(seed: u64, tick: Duration) -> Self {
+        Self {
+            seed,
+            tick,
+            ticks: AtomicU64::new(0),
+        }
+    }
+
+    pub fn advance(&self) {
+        self.ticks.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Nanoseconds since the first tick, reported as the time of every
+    /// clock.
+    fn now(&self) -> u64 {
+        self.ticks.load(Ordering::SeqCst) * self.tick.as_nanos() as u64
+    }
+
+    /// Seed of the plugin named `plugin`, so the random numbers a plugin
+    /// gets do not depend on the other plugins.
+    fn seed_for(&self, plugin: &str) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&blake3::hash(plugin.as_bytes()).as_bytes()[..8]);
+        self.seed ^ u64::from_le_bytes(bytes)
+    }
+}
+
+#[derive(Clone)]
+struct DeterministicEnv {
+    memory: LazyInit<Memory>,
+    clock: Arc<Deterministic>,
+    rng: Arc<Mutex<u64>>,
+    subscription_len: u32,
+}
+
+impl WasmerEnv for DeterministicEnv {
+    fn init_with_instance(&mut self, instance: &Instance) -> Result<(), HostEnvInitError> {
+        let memory = instance.exports.get_memory("memory")?;
+        self.memory.initialize(memory.clone());
+        Ok(())
+    }
+}
+
+impl DeterministicEnv {
+    fn memory(&self) -> Result<&Memory, PluginError> {
+        self.memory
+            .get_ref()
+            .ok_or_else(|| ExportError::Missing("memory".to_owned()).into())
+    }
+}
+
+/// Replaces the WASI clock, random and polling functions in `imports` with
+/// ones backed by `clock`.
+///
+/// Clock subscriptions of `poll_oneoff` elapse right away, as the tick clock
+/// does not advance during a call, and file subscriptions are not supported.
+/// The timestamps of files in the plugin's data directory are left as is.
+pub fn override_wasi(
+    store: &Store,
+    imports: &mut ImportObject,
+    clock: Arc<Deterministic>,
+    plugin: &str,
+) {
+    let rng = Arc::new(Mutex::new(clock.seed_for(plugin)));
+    for &(namespace, subscription_len) in WASI.iter() {
+        // The plugin does not use this version of WASI.
+        let mut exports = match imports.get_namespace_exports(namespace) {
+            Some(exports) => exports,
+            None => continue,
+        };
+
+        let env = DeterministicEnv {
+            memory: Default::default(),
+            clock: clock.clone(),
+            rng: rng.clone(),
+            subscription_len,
+        };
+        exports.insert(
+            "clock_time_get",
+            Function::new_native_with_env(store, env.clone(), clock_time_get),
+        );
+        exports.insert(
+            "clock_res_get",
+            Function::new_native_with_env(store, env.clone(), clock_res_get),
+        );
+        exports.insert(
+            "random_get",
+            Function::new_native_with_env(store, env.clone(), random_get),
+        );
+        exports.insert(
+            "poll_oneoff",
+            Function::new_native_with_env(store, env, poll_oneoff),
+        );
+        imports.register(namespace, exports);
+    }
+}
+
+fn clock_time_get(
+    env: &DeterministicEnv,
+    _clock: u32,
+    _precision: u64,
+    time: WasmPtr<u64>,
+) -> Result<u32, PluginError> {
+    Ok(match time.deref(env.memory()?) {
+        Some(time) => {
+            time.set(env.clock.now());
+            ESUCCESS
+        }
+        None => EFAULT,
+    })
+}
+
+fn clock_res_get(
+    env: &DeterministicEnv,
+    _clock: u32,
+    resolution: WasmPtr<u64>,
+) -> Result<u32, PluginError> {
+    Ok(match resolution.deref(env.memory()?) {
+        Some(resolution) => {
+            resolution.set(env.clock.tick.as_nanos() as u64);
+            ESUCCESS
+        }
+        None => EFAULT,
+    })
+}
+
+fn random_get(
+    env: &DeterministicEnv,
+    buffer: WasmPtr<u8, Array>,
+    len: u32,
+) -> Result<u32, PluginError> {
+    let cells = match buffer.deref(env.memory()?, 0, len) {
+        Some(cells) => cells,
+        None => return Ok(EFAULT),
+    };
+    let mut state = lock(&env.rng, "rng")?;
+    for chunk in cells.chunks(8) {
+        let bytes = splitmix64(&mut state).to_le_bytes();
+        for (cell, byte) in chunk.iter().zip(bytes.iter()) {
+            cell.set(*byte);
+        }
+    }
+    Ok(ESUCCESS)
+}
+
+fn poll_oneoff(
+    env: &DeterministicEnv,
+    subscriptions: WasmPtr<u8, Array>,
+    events: WasmPtr<u8, Array>,
+    count: u32,
+    events_len: WasmPtr<u32>,
+) -> Result<u32, PluginError> {
+    if count == 0 {
+        return Ok(EINVAL);
+    }
+    let memory = env.memory()?;
+    let subscriptions = count
+        .checked_mul(env.subscription_len)
+        .and_then(|len| subscriptions.deref(memory, 0, len));
+    let events = count
+        .checked_mul(EVENT_LEN)
+        .and_then(|len| events.deref(memory, 0, len));
+    let (subscriptions, events, events_len) =
+        match (subscriptions, events, events_len.deref(memory)) {
+            (Some(subscriptions), Some(events), Some(events_len)) => {
+                (subscriptions, events, events_len)
+            }
+            _ => return Ok(EFAULT),
+        };
+
+    let subscriptions = subscriptions.chunks(env.subscription_len as usize);
+    for (subscription, cells) in subscriptions.zip(events.chunks(EVENT_LEN as usize)) {
+        let mut head = [0; 9];
+        for (byte, cell) in head.iter_mut().zip(subscription) {
+            *byte = cell.get();
+        }
+        for (cell, byte) in cells.iter().zip(event(&head).iter()) {
+            cell.set(*byte);
+        }
+    }
+    events_len.set(count);
+    Ok(ESUCCESS)
+}
+
+/// The event `poll_oneoff` reports for `subscription`, which starts with its
+/// userdata followed by its type.
+fn event(subscription: &[u8]) -> [u8; EVENT_LEN as usize] {
+    let kind = subscription[8];
+    let error = match kind {
+        EVENTTYPE_CLOCK => ESUCCESS as u16,
+        _ => ENOTSUP,
+    };
+    // Events start with the userdata of their subscription, followed by an
+    // error and the subscription's type.
+    let mut event = [0; EVENT_LEN as usize];
+    event[..8].copy_from_slice(&subscription[..8]);
+    event[8..10].copy_from_slice(&error.to_le_bytes());
+    event[10] = kind;
+    event
+}
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscription(userdata: u64, kind: u8, len: u32) -> Vec<u8> {
+        let mut subscription = vec![0xff; len as usize];
+        subscription[..8].copy_from_slice(&userdata.to_le_bytes());
+        subscription[8] = kind;
+        subscription
+    }
+
+    #[test]
+    fn clock_event() {
+        for &(_, len) in WASI.iter() {
+            let event = event(&subscription(0x0102_0304_0506_0708, EVENTTYPE_CLOCK, len));
+            assert_eq!(event[..8], 0x0102_0304_0506_0708u64.to_le_bytes());
+            assert_eq!(event[8..10], (ESUCCESS as u16).to_le_bytes());
+            assert_eq!(event[10], EVENTTYPE_CLOCK);
+            assert!(event[11..].iter().all(|&byte| byte == 0));
+        }
+    }
+
+    #[test]
+    fn unsupported_event() {
+        // `fd_read`, the first type that is not a clock.
+        let event = event(&subscription(7, 1, 48));
+        assert_eq!(event[..8], 7u64.to_le_bytes());
+        assert_eq!(event[8..10], ENOTSUP.to_le_bytes());
+        assert_eq!(event[10], 1);
+        assert!(event[11..].iter().all(|&byte| byte == 0));
+    }
+}
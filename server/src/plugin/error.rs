@@ -0,0 +1,94 @@
+use std::{
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+
+use quill::{
+    envelope::EnvelopeError,
+    rpc::{RpcError, RpcId},
+};
+use thiserror::Error;
+use wasmer::{ExportError, RuntimeError};
+
+use super::{CallKind, Capability};
+
+#[derive(Debug, Error)]
+pub enum PluginError {
+    #[error("no rpc named {0}")]
+    UnknownRpc(String),
+    #[error("no rpc with id {0}")]
+    UnknownRpcId(RpcId),
+    #[error("could not decode rpc call: {0}")]
+    Decode(bincode::Error),
+    #[error("invalid message envelope: {0}")]
+    Envelope(EnvelopeError),
+    #[error("could not encode rpc result: {0}")]
+    Encode(bincode::Error),
+    #[error("invalid rpc arguments: {0}")]
+    InvalidArguments(String),
+    #[error("cannot resolve more than {0} rpcs")]
+    ResolveLimit(usize),
+    #[error("plugin trapped: {}", .0.message())]
+    Trap(#[from] RuntimeError),
+    #[error("plugin is missing an export: {0}")]
+    MissingExport(#[from] ExportError),
+    #[error("buffer is outside of the plugin's memory")]
+    OutOfBounds,
+    #[error("{0} is poisoned by an earlier panic")]
+    Poisoned(&'static str),
+    #[error("plugin ran out of fuel during {0}")]
+    FuelExhausted(CallKind),
+    #[error("call was interrupted as it ran past its deadline")]
+    Interrupted,
+    #[error("plugin did not finish {0} within {1:?}")]
+    TimedOut(CallKind, Duration),
+    #[error("plugin {0} is quarantined and will not be called")]
+    Quarantined(String),
+    #[error("plugin could not allocate {requested} more bytes of buffer")]
+    OutOfMemory { requested: u32 },
+    #[error("plugin could not handle rpc: {0}")]
+    Rpc(RpcError),
+    #[error("calling {rpc} requires the {capability} capability")]
+    PermissionDenied { rpc: String, capability: Capability },
+    #[error("no plugin named {0} is enabled")]
+    UnknownPlugin(String),
+    #[error("plugin {plugin} failed: {error}")]
+    Peer {
+        plugin: String,
+        error: Box<PluginError>,
+    },
+}
+
+impl PluginError {
+    /// Converts errors caused by the guest's request into an error that is
+    /// reported back to it, while errors that leave the plugin in an unknown
+    /// state are returned as is to trap it.
+    pub fn report(self) -> Result<RpcError, PluginError> {
+        match self {
+            PluginError::UnknownRpc(name) => Ok(RpcError::UnknownRpc(name)),
+            PluginError::UnknownRpcId(id) => Ok(RpcError::UnknownRpcId(id)),
+            PluginError::Decode(error) => Ok(RpcError::Decode(error.to_string())),
+            PluginError::Envelope(error) => Ok(RpcError::Decode(error.to_string())),
+            PluginError::InvalidArguments(message) => Ok(RpcError::InvalidArguments(message)),
+            PluginError::ResolveLimit(limit) => Ok(RpcError::ResolveLimit(limit as u32)),
+            PluginError::UnknownPlugin(plugin) => Ok(RpcError::UnknownPlugin(plugin)),
+            PluginError::PermissionDenied { rpc, capability } => Ok(RpcError::PermissionDenied {
+                rpc,
+                capability: capability.to_string(),
+            }),
+            // The plugin that was called failed, not the caller.
+            PluginError::Peer { plugin, error } => Ok(RpcError::PluginFailed {
+                plugin,
+                message: error.to_string(),
+            }),
+            error => Err(error),
+        }
+    }
+}
+
+pub fn lock<'a, T>(
+    mutex: &'a Mutex<T>,
+    name: &'static str,
+) -> Result<MutexGuard<'a, T>, PluginError> {
+    mutex.lock().map_err(|_| PluginError::Poisoned(name))
+}
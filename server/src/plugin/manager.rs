@@ -0,0 +1,371 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Result};
+use bevy_ecs::{Entity, World};
+use semver::Version;
+
+use super::{
+    dependencies::{check_dependencies, check_dependents, check_unload, load_order},
+    determinism::Deterministic,
+    watchdog::Watchdog,
+    Backend, EntityPolicy, ModuleCache, Plugin, PluginConfig, PluginError, PluginFile,
+    ProfileReport, Shared,
+};
+
+/// Owns every loaded plugin and the state they share.
+pub struct PluginManager {
+    shared: Shared,
+    /// Ordered by name so plugins are ticked in the same order every run.
+    plugins: BTreeMap<String, Plugin>,
+    default_config: PluginConfig,
+    /// Per plugin overrides of `default_config`.
+    configs: HashMap<String, PluginConfig>,
+    /// Modification times of rebuilt files that failed to reload, so a broken
+    /// build is only reported once.
+    failed_reloads: HashMap<PathBuf, SystemTime>,
+}
+
+/// A plugin that could not be loaded by [`PluginManager::load_dir`].
+#[derive(Debug)]
+pub struct LoadFailure {
+    pub path: PathBuf,
+    pub error: anyhow::Error,
+}
+
+impl PluginManager {
+    pub fn new(world: Arc<Mutex<World>>) -> Self {
+        Self {
+            shared: Shared {
+                world,
+                layouts: Default::default(),
+                cache: None,
+                backend: Backend::default(),
+                trap_log: None,
+                data_dir: PathBuf::from("./data/plugins"),
+                peers: Default::default(),
+                watchdog: Watchdog::spawn(),
+                deterministic: None,
+            },
+            plugins: BTreeMap::new(),
+            default_config: PluginConfig::default(),
+            configs: HashMap::new(),
+            failed_reloads: HashMap::new(),
+        }
+    }
+
+    /// Caches compiled modules in `dir`, speeding up later loads.
+    pub fn set_cache_dir<P: AsRef<Path>>(&mut self, dir: P) {
+        self.shared.cache = Some(Arc::new(ModuleCache::new(dir)));
+    }
+
+    /// Gives each plugin a data directory in `dir`.
+    pub fn set_data_dir<P: AsRef<Path>>(&mut self, dir: P) {
+        self.shared.data_dir = dir.as_ref().to_owned();
+    }
+
+    /// Appends trap reports of plugins to `path`.
+    pub fn set_trap_log<P: AsRef<Path>>(&mut self, path: P) {
+        self.shared.trap_log = Some(path.as_ref().to_owned());
+    }
+
+    /// Backs the WASI clocks and randomness of plugins loaded from now on by
+    /// `seed` and the number of ticks, `tick` apart, so running the same
+    /// plugins gives the same results.
+    pub fn set_deterministic(&mut self, seed: u64, tick: Duration) {
+        self.shared.deterministic = Some(Arc::new(Deterministic::new(seed, tick)));
+    }
+
+    /// Sets the compiler used for plugins loaded from now on.
+    pub fn set_backend(&mut self, backend: Backend) -> Result<()> {
+        if !backend.is_available() {
+            bail!("the {} backend was not compiled in", backend);
+        }
+        self.shared.backend = backend;
+        Ok(())
+    }
+
+    /// Sets the config used by plugins without a config of their own.
+    pub fn set_default_config(&mut self, config: PluginConfig) {
+        self.default_config = config;
+    }
+
+    /// Sets the config of the plugin named `name`, taking effect the next
+    /// time it is loaded.
+    pub fn set_config(&mut self, name: &str, config: PluginConfig) {
+        self.configs.insert(name.to_owned(), config);
+    }
+
+    pub fn config(&self, name: &str) -> &PluginConfig {
+        self.configs.get(name).unwrap_or(&self.default_config)
+    }
+
+    /// Loads every `.wasm` file in `dir`, each plugin after the plugins it
+    /// depends on.
+    ///
+    /// A plugin failing to load does not stop the others from loading, the
+    /// failures are returned instead. Plugins with missing or cyclic
+    /// dependencies fail to load, as do plugins requiring a plugin that
+    /// failed.
+    pub fn load_dir<P: AsRef<Path>>(&mut self, dir: P) -> Result<Vec<LoadFailure>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() && path.extension() == Some(OsStr::new("wasm")) {
+                paths.push(path);
+            }
+        }
+        // Directory order is platform dependent.
+        paths.sort();
+
+        let mut failures = Vec::new();
+        let mut files = Vec::new();
+        for path in paths {
+            match PluginFile::read(&path) {
+                Ok(file) => files.push(file),
+                Err(error) => failures.push(LoadFailure { path, error }),
+            }
+        }
+
+        let (order, unordered) = load_order(files, &self.versions());
+        failures.extend(unordered);
+        for file in order {
+            let path = file.path.clone();
+            if let Err(error) = self.load_file(file) {
+                failures.push(LoadFailure { path, error });
+            }
+        }
+        Ok(failures)
+    }
+
+    /// Loads the plugin at `path`, whose dependencies have to be loaded
+    /// already.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<&Plugin> {
+        self.load_file(PluginFile::read(path)?)
+    }
+
+    fn load_file(&mut self, file: PluginFile) -> Result<&Plugin> {
+        let name = file.manifest.name.clone();
+        if self.plugins.contains_key(&name) {
+            bail!("a plugin named {} is already loaded", name);
+        }
+        check_dependencies(&file.manifest, &self.versions())?;
+        let config = self.config(&name).clone();
+        let plugin = enable(Plugin::load(file, config, &self.shared)?)?;
+        Ok(self.plugins.entry(name).or_insert(plugin))
+    }
+
+    /// Reloads every plugin whose wasm file changed since it was loaded.
+    ///
+    /// The old instance is only replaced once the new one loaded and was
+    /// enabled successfully, after which the old one is disabled. The new
+    /// version has to satisfy the dependencies of the other plugins.
+    /// Entities spawned by the old instance are kept and handed over to the
+    /// new one, as is the state it saves through [`Plugin::save_state`].
+    pub fn reload_changed(&mut self) -> Vec<LoadFailure> {
+        let changed: Vec<(String, PathBuf, SystemTime)> = self
+            .plugins
+            .values()
+            .filter_map(|plugin| {
+                let modified = modified(plugin.path())?;
+                let known = self.failed_reloads.get(plugin.path());
+                if modified != plugin.modified() && known != Some(&modified) {
+                    Some((plugin.name().to_owned(), plugin.path().to_owned(), modified))
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        let mut failures = Vec::new();
+        for (name, path, modified) in changed {
+            match self.replace(&name, &path) {
+                Ok(()) => {
+                    self.failed_reloads.remove(&path);
+                }
+                Err(error) => {
+                    self.failed_reloads.insert(path.clone(), modified);
+                    failures.push(LoadFailure { path, error });
+                }
+            }
+        }
+        failures
+    }
+
+    /// Replaces the quarantined plugin `name` with a new instance of its wasm
+    /// file, e.g. once it was fixed. The instance that trapped is not reused
+    /// as it might have been left in an inconsistent state.
+    pub fn reenable(&mut self, name: &str) -> Result<()> {
+        let path = match self.plugins.get(name) {
+            Some(plugin) if plugin.is_quarantined() => plugin.path().to_owned(),
+            Some(_) => bail!("plugin {} is not quarantined", name),
+            None => bail!("no plugin named {} is loaded", name),
+        };
+        self.replace(name, &path)
+    }
+
+    /// Replaces the plugin `name` with a new instance loaded from `path`,
+    /// see [`PluginManager::reload_changed`].
+    fn replace(&mut self, name: &str, path: &Path) -> Result<()> {
+        let file = PluginFile::read(path)?;
+        if file.manifest.name != name {
+            bail!(
+                "plugin {} was renamed to {}, unload it first",
+                name,
+                file.manifest.name
+            );
+        }
+        let mut versions = self.versions();
+        versions.remove(name);
+        check_dependencies(&file.manifest, &versions)?;
+        check_dependents(
+            &file.manifest,
+            self.plugins
+                .values()
+                .filter(|plugin| plugin.name() != name)
+                .map(Plugin::manifest),
+        )?;
+
+        let config = self.config(name).clone();
+        let mut plugin = Plugin::load(file, config, &self.shared)?;
+        if let Some(old) = self.plugins.get_mut(name) {
+            if let Err(error) = transfer_state(old, &mut plugin) {
+                plugin.unload(EntityPolicy::Despawn)?;
+                return Err(error.into());
+            }
+        }
+        let plugin = enable(plugin)?;
+        let result = match self.plugins.remove(name) {
+            Some(old) => old
+                .unload(EntityPolicy::Keep)
+                .and_then(|orphans| Ok(plugin.adopt(orphans)?)),
+            None => Ok(()),
+        };
+        self.plugins.insert(name.to_owned(), plugin);
+        result
+    }
+
+    /// Ticks every enabled plugin. Failures are logged, through trap reports
+    /// if the plugin trapped, and only stop the plugin from being ticked once
+    /// it is quarantined.
+    pub fn tick(&mut self) {
+        if let Some(deterministic) = &self.shared.deterministic {
+            deterministic.advance();
+        }
+        for plugin in self.plugins.values_mut() {
+            if plugin.is_enabled() && !plugin.is_quarantined() {
+                // Logged by the plugin already.
+                let _ = plugin.tick();
+            }
+        }
+    }
+
+    /// Unloads the plugin named `name`, see [`Plugin::unload`]. The layouts
+    /// of entities that are kept stay known as no plugin adopts them.
+    ///
+    /// Plugins that require it have to be unloaded first.
+    pub fn unload(&mut self, name: &str, policy: EntityPolicy) -> Result<Vec<Entity>> {
+        if !self.plugins.contains_key(name) {
+            bail!("no plugin named {} is loaded", name);
+        }
+        check_unload(
+            name,
+            self.plugins
+                .values()
+                .filter(|plugin| plugin.name() != name)
+                .map(Plugin::manifest),
+        )?;
+        match self.plugins.remove(name) {
+            Some(plugin) => Ok(plugin.unload(policy)?.entities),
+            None => bail!("no plugin named {} is loaded", name),
+        }
+    }
+
+    /// The profiles of every loaded plugin, see [`Plugin::profile`].
+    pub fn profile(&self) -> ProfileReport {
+        ProfileReport {
+            plugins: self
+                .plugins
+                .iter()
+                .map(|(name, plugin)| (name.clone(), plugin.profile().snapshot()))
+                .collect(),
+        }
+    }
+
+    pub fn reset_profiles(&self) {
+        for plugin in self.plugins.values() {
+            plugin.profile().reset();
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Plugin> {
+        self.plugins.get(name)
+    }
+
+    pub fn plugins(&self) -> impl Iterator<Item = &Plugin> {
+        self.plugins.values()
+    }
+
+    /// Plugins that are not called anymore because they trapped too often.
+    pub fn quarantined(&self) -> impl Iterator<Item = &Plugin> {
+        self.plugins
+            .values()
+            .filter(|plugin| plugin.is_quarantined())
+    }
+
+    pub fn world(&self) -> &Arc<Mutex<World>> {
+        &self.shared.world
+    }
+
+    /// Versions of the loaded plugins by name.
+    fn versions(&self) -> HashMap<String, Version> {
+        self.plugins
+            .values()
+            .map(|plugin| (plugin.name().to_owned(), plugin.manifest().version.clone()))
+            .collect()
+    }
+}
+
+/// Enables a freshly loaded plugin, unloading it again if that fails.
+fn enable(mut plugin: Plugin) -> Result<Plugin> {
+    match plugin.enable() {
+        Ok(()) => Ok(plugin),
+        Err(error) => {
+            plugin.unload(EntityPolicy::Despawn)?;
+            Err(error.into())
+        }
+    }
+}
+
+/// Moves the state of `old` into its replacement `new` before `new` is
+/// enabled. A quarantined plugin's state is left behind as the trap might
+/// have left it inconsistent, as is state that could not be saved.
+fn transfer_state(old: &mut Plugin, new: &mut Plugin) -> Result<(), PluginError> {
+    if old.is_quarantined() {
+        return Ok(());
+    }
+    match old.save_state() {
+        Ok(Some(state)) => new.load_state(&state),
+        Ok(None) => Ok(()),
+        Err(error) => {
+            tracing::warn!(
+                plugin = %old.name(),
+                "reloading without state as it could not be saved: {}",
+                error
+            );
+            Ok(())
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Context, Result};
+use semver::{Version, VersionReq};
+use serde::Deserialize;
+
+use super::Capability;
+
+/// Metadata a plugin embeds using `quill::manifest!`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub name: String,
+    pub version: Version,
+    #[serde(default)]
+    pub authors: Vec<String>,
+    /// Protocol version of the quill the plugin was built against.
+    pub quill: u32,
+    /// Capabilities the plugin needs, it only gets those granted to it.
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    /// Plugins that have to be loaded before this one.
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, VersionReq>,
+    /// Plugins that are loaded before this one if they are present.
+    #[serde(default)]
+    pub optional_dependencies: BTreeMap<String, VersionReq>,
+}
+
+/// The fields every version of the manifest has, read before the others so
+/// plugins built against another protocol are refused as such even if their
+/// manifest has fields this one does not know.
+#[derive(Deserialize)]
+struct Protocol {
+    name: String,
+    quill: u32,
+}
+
+impl Manifest {
+    /// Reads the manifest from the custom section of `wasm`, refusing
+    /// plugins built against another version of the quill protocol.
+    pub fn from_wasm(wasm: &[u8]) -> Result<Self> {
+        let section = custom_section(wasm, quill::manifest::SECTION)?
+            .context("plugin has no manifest, was it built with quill::manifest!?")?;
+        let protocol: Protocol = toml::from_slice(section).context("malformed manifest")?;
+        if protocol.quill != quill::PROTOCOL_VERSION {
+            bail!(
+                "plugin {} was built against quill protocol {} but the host uses {}",
+                protocol.name,
+                protocol.quill,
+                quill::PROTOCOL_VERSION
+            );
+        }
+
+        let manifest: Manifest = toml::from_slice(section).context("malformed manifest")?;
+        // The name is used for paths and to namespace rpcs.
+        let valid_name = !manifest.name.is_empty()
+            && manifest
+                .name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if !valid_name {
+            bail!(
+                "plugin name {:?} may only contain ascii letters, digits, - and _",
+                manifest.name
+            );
+        }
+        Ok(manifest)
+    }
+
+    /// Required and optional dependencies.
+    pub fn all_dependencies(&self) -> impl Iterator<Item = (&String, &VersionReq)> {
+        self.dependencies
+            .iter()
+            .chain(self.optional_dependencies.iter())
+    }
+}
+
+/// Finds the contents of the custom section `name` without compiling `wasm`.
+fn custom_section<'a>(wasm: &'a [u8], name: &str) -> Result<Option<&'a [u8]>> {
+    if wasm.len() < 8 || &wasm[..4] != b"\0asm" {
+        bail!("not a wasm module");
+    }
+
+    let mut rest = &wasm[8..];
+    while !rest.is_empty() {
+        let id = rest[0];
+        rest = &rest[1..];
+        let size = read_u32(&mut rest)? as usize;
+        if rest.len() < size {
+            bail!("truncated wasm section");
+        }
+        let (mut section, next) = rest.split_at(size);
+        rest = next;
+
+        // Custom sections have id 0 and start with their name.
+        if id == 0 {
+            let name_len = read_u32(&mut section)? as usize;
+            if section.len() < name_len {
+                bail!("truncated wasm section");
+            }
+            if &section[..name_len] == name.as_bytes() {
+                return Ok(Some(&section[name_len..]));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Reads an unsigned LEB128 encoded integer.
+fn read_u32(bytes: &mut &[u8]) -> Result<u32> {
+    let mut value = 0u32;
+    for shift in (0..35).step_by(7) {
+        let (&byte, rest) = bytes.split_first().context("truncated wasm section")?;
+        *bytes = rest;
+        value |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("malformed wasm integer")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A module with the sections `(id, contents)`, which have to be shorter
+    /// than 128 bytes.
+    fn module(sections: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut wasm = b"\0asm\x01\0\0\0".to_vec();
+        for (id, contents) in sections {
+            wasm.push(*id);
+            wasm.push(contents.len() as u8);
+            wasm.extend_from_slice(contents);
+        }
+        wasm
+    }
+
+    fn custom(name: &str, contents: &[u8]) -> Vec<u8> {
+        let mut section = vec![name.len() as u8];
+        section.extend_from_slice(name.as_bytes());
+        section.extend_from_slice(contents);
+        section
+    }
+
+    #[test]
+    fn finds_custom_section() {
+        let other = custom("other", b"ignored");
+        let manifest = custom("quill_manifest", b"contents");
+        let wasm = module(&[(1, b"\x00"), (0, &other), (0, &manifest)]);
+        assert_eq!(
+            custom_section(&wasm, "quill_manifest").unwrap(),
+            Some(&b"contents"[..])
+        );
+    }
+
+    #[test]
+    fn missing_custom_section() {
+        let other = custom("other", b"ignored");
+        let wasm = module(&[(1, b"\x00"), (0, &other)]);
+        assert_eq!(custom_section(&wasm, "quill_manifest").unwrap(), None);
+        assert!(custom_section(b"\0asm", "quill_manifest").is_err());
+    }
+
+    #[test]
+    fn truncated_custom_section() {
+        let manifest = custom("quill_manifest", b"contents");
+        let mut wasm = module(&[(0, &manifest)]);
+        wasm.pop();
+        let error = custom_section(&wasm, "quill_manifest").unwrap_err();
+        assert_eq!(error.to_string(), "truncated wasm section");
+
+        // The name is longer than the section.
+        let wasm = module(&[(0, b"\x05ab")]);
+        assert!(custom_section(&wasm, "ab").is_err());
+    }
+
+    #[test]
+    fn malformed_leb128() {
+        // Every byte has its continuation bit set.
+        let mut wasm = module(&[]);
+        wasm.extend_from_slice(&[0, 0x80, 0x80, 0x80, 0x80, 0x80, 0x01]);
+        let error = custom_section(&wasm, "quill_manifest").unwrap_err();
+        assert_eq!(error.to_string(), "malformed wasm integer");
+
+        let mut bytes = &[0xe5, 0x8e, 0x26][..];
+        assert_eq!(read_u32(&mut bytes).unwrap(), 624_485);
+        assert!(bytes.is_empty());
+        assert!(read_u32(&mut &[0x80][..]).is_err());
+    }
+
+    #[test]
+    fn refuses_other_protocol_before_parsing() {
+        let toml = format!(
+            "quill = {}\nname = \"future\"\nversion = \"1.0.0\"\nnew_field = 1\n",
+            quill::PROTOCOL_VERSION + 1
+        );
+        let manifest = custom(quill::manifest::SECTION, toml.as_bytes());
+        let error = Manifest::from_wasm(&module(&[(0, &manifest)])).unwrap_err();
+        assert!(error.to_string().contains("quill protocol"), "{}", error);
+    }
+}
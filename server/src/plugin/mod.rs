@@ -3,43 +3,108 @@ use std::{
     array::TryFromSliceError,
     borrow::BorrowMut,
     cell::{Cell, UnsafeCell},
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     ffi::OsStr,
     fs,
     io::{self, Read, Write},
     mem,
     ops::{Deref, DerefMut},
-    path::Path,
-    sync::{Arc, Mutex, MutexGuard},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, SystemTime},
     todo, u32, vec,
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use bevy_ecs::{
-    ComponentId, DynamicFetch, DynamicFetchResult, DynamicQuery, DynamicSystem, EntityBuilder,
-    QueryAccess, StatefulQuery, TypeAccess, TypeInfo, World,
+    ComponentId, DynamicFetch, DynamicFetchResult, DynamicQuery, DynamicSystem, Entity,
+    EntityBuilder, QueryAccess, StatefulQuery, TypeAccess, TypeInfo, World,
 };
 use bincode::DefaultOptions;
 use fs::OpenOptions;
 use io::IoSlice;
 use mem::ManuallyDrop;
-use quill::ecs::TypeLayout;
+use quill::{
+    ecs::TypeLayout,
+    envelope::{self, Header, MessageKind, HEADER_LEN, LEN_OFFSET},
+    rpc::{RpcError, RpcId, RpcSignature, RpcTarget},
+};
 use wasmer::{
-    import_namespace, imports, Array, FromToNativeWasmType, Function, HostEnvInitError, Instance,
-    LazyInit, Memory, Module, NativeFunc, Store, Type, ValueType, WasmPtr, WasmTypeList, WasmerEnv,
-    JIT, LLVM,
+    import_namespace, imports, wasmparser::Operator, Array, BaseTunables, CompilerConfig,
+    ExportError, FromToNativeWasmType, Function, HostEnvInitError, Instance, LazyInit, Memory,
+    Module, NativeFunc, Pages, Store, Target, Type, ValueType, WasmPtr, WasmTypeList, WasmerEnv,
+    JIT,
 };
+use wasmer_middlewares::Metering;
 use wasmer_wasi::WasiState;
 
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-#[derive(Default)]
+mod backend;
+mod cache;
+mod capability;
+mod config;
+mod dependencies;
+mod determinism;
+mod error;
+mod manager;
+mod manifest;
+mod output;
+mod peer;
+mod profile;
+mod trap;
+mod tunables;
+mod watchdog;
+
+pub use backend::Backend;
+pub use cache::ModuleCache;
+pub use capability::Capability;
+pub use config::{CallKind, FuelLimits, PluginConfig};
+use determinism::Deterministic;
+use error::lock;
+pub use error::PluginError;
+pub use manager::{LoadFailure, PluginManager};
+pub use manifest::Manifest;
+use output::{LogOutput, Stream};
+use peer::{Peer, Peers};
+pub use profile::{Profile, ProfileReport, Site};
+pub use trap::{Frame, TrapReport};
+use tunables::LimitingTunables;
+use watchdog::{Interrupt, Watchdog};
+
 struct PluginEnv<S> {
     memory: LazyInit<Memory>,
-    buffer_reserve: LazyInit<NativeFunc<(WasmPtr<RawBuffer>, u32)>>,
-    rpcs: Arc<Mutex<HashMap<String, Box<dyn Fn(&mut Buffer, &PluginEnv<S>) -> Result<()> + Send>>>>,
+    buffer_reserve: LazyInit<NativeFunc<(WasmPtr<RawBuffer>, u32), u32>>,
+    buffer_new: LazyInit<NativeFunc<(), WasmPtr<RawBuffer>>>,
+    client_call: LazyInit<NativeFunc<WasmPtr<RawBuffer>, ()>>,
+    /// Buffers in guest memory for calls into the guest, one is taken for
+    /// every call in flight.
+    client_buffers: Arc<Mutex<Vec<WasmPtr<RawBuffer>>>>,
+    /// Id of the last call into the guest, its reply has to echo it.
+    call_id: Arc<AtomicU32>,
+    rpcs: Arc<Mutex<HashMap<String, Arc<HostRpc<S>>>>>,
+    resolved_rpcs: Arc<Mutex<ResolvedRpcs<S>>>,
+    /// Capabilities the plugin requested and was granted.
+    capabilities: Arc<HashSet<Capability>>,
+    /// Rpcs the guest announced it handles.
+    guest_rpcs: Arc<Mutex<HashMap<String, RpcSignature>>>,
     state: Arc<Mutex<S>>,
     layouts: Arc<Mutex<Layouts>>,
+    /// Entities spawned by the plugin.
+    entities: Arc<Mutex<Vec<Entity>>>,
+    /// Layouts the plugin holds a reference to in `layouts`.
+    used_layouts: Arc<Mutex<HashSet<TypeLayout>>>,
+    /// The host rpc currently being handled, left set if it failed.
+    rpc_in_flight: Arc<Mutex<Option<String>>>,
+    /// The guest rpc currently being called, left set if the guest trapped.
+    guest_rpc_in_flight: Arc<Mutex<Option<String>>>,
+    /// Set by the watchdog once the call in flight ran past its deadline.
+    interrupted: Arc<AtomicBool>,
+    peers: Peers,
+    profile: Arc<Profile>,
 }
 
 impl<S: Send + Sync + 'static> Clone for PluginEnv<S> {
@@ -47,9 +112,23 @@ impl<S: Send + Sync + 'static> Clone for PluginEnv<S> {
         Self {
             memory: self.memory.clone(),
             buffer_reserve: self.buffer_reserve.clone(),
+            buffer_new: self.buffer_new.clone(),
+            client_call: self.client_call.clone(),
+            client_buffers: self.client_buffers.clone(),
+            call_id: self.call_id.clone(),
             rpcs: self.rpcs.clone(),
+            resolved_rpcs: self.resolved_rpcs.clone(),
+            capabilities: self.capabilities.clone(),
+            guest_rpcs: self.guest_rpcs.clone(),
             state: self.state.clone(),
-            layouts: Default::default(),
+            layouts: self.layouts.clone(),
+            entities: self.entities.clone(),
+            used_layouts: self.used_layouts.clone(),
+            rpc_in_flight: self.rpc_in_flight.clone(),
+            guest_rpc_in_flight: self.guest_rpc_in_flight.clone(),
+            interrupted: self.interrupted.clone(),
+            peers: self.peers.clone(),
+            profile: self.profile.clone(),
         }
     }
 }
@@ -63,26 +142,87 @@ impl<S: Send + Sync + 'static> WasmerEnv for PluginEnv<S> {
                 .exports
                 .get_native_function("__quill_buffer_reserve")?,
         );
+        self.buffer_new
+            .initialize(instance.exports.get_native_function("__quill_buffer_new")?);
+        self.client_call.initialize(
+            instance
+                .exports
+                .get_native_function("__quill_client_call")?,
+        );
         Ok(())
     }
 }
 
 impl<S: Send + Sync + 'static> PluginEnv<S> {
-    fn memory(&self) -> &Memory {
-        // TODO: handle errors.
-        self.memory.get_ref().unwrap()
+    fn new(
+        state: Arc<Mutex<S>>,
+        layouts: Arc<Mutex<Layouts>>,
+        peers: Peers,
+        capabilities: HashSet<Capability>,
+    ) -> Self {
+        Self {
+            memory: Default::default(),
+            buffer_reserve: Default::default(),
+            buffer_new: Default::default(),
+            client_call: Default::default(),
+            client_buffers: Default::default(),
+            call_id: Default::default(),
+            rpcs: Default::default(),
+            resolved_rpcs: Default::default(),
+            capabilities: Arc::new(capabilities),
+            guest_rpcs: Default::default(),
+            state,
+            layouts,
+            entities: Default::default(),
+            used_layouts: Default::default(),
+            rpc_in_flight: Default::default(),
+            guest_rpc_in_flight: Default::default(),
+            interrupted: Default::default(),
+            peers,
+            profile: Default::default(),
+        }
     }
 
-    fn buffer_reserve(&self) -> &NativeFunc<(WasmPtr<RawBuffer>, u32)> {
-        self.buffer_reserve.get_ref().unwrap()
+    /// Marks `layout` as used by this plugin, keeping it alive in `layouts`
+    /// until the plugin is unloaded.
+    fn use_layout(&self, layouts: &mut Layouts, layout: &TypeLayout) -> Result<(), PluginError> {
+        let mut used = lock(&self.used_layouts, "used layouts")?;
+        if used.insert(layout.clone()) {
+            layouts.acquire(layout);
+        }
+        Ok(())
     }
 
-    fn buffer(&self, raw: WasmPtr<RawBuffer>) -> Buffer {
-        Buffer {
-            memory: self.memory(),
-            reserve: self.buffer_reserve(),
+    fn memory(&self) -> Result<&Memory, PluginError> {
+        self.memory
+            .get_ref()
+            .ok_or_else(|| ExportError::Missing("memory".to_owned()).into())
+    }
+
+    fn buffer_reserve(&self) -> Result<&NativeFunc<(WasmPtr<RawBuffer>, u32), u32>, PluginError> {
+        self.buffer_reserve
+            .get_ref()
+            .ok_or_else(|| ExportError::Missing("__quill_buffer_reserve".to_owned()).into())
+    }
+
+    fn buffer_new(&self) -> Result<&NativeFunc<(), WasmPtr<RawBuffer>>, PluginError> {
+        self.buffer_new
+            .get_ref()
+            .ok_or_else(|| ExportError::Missing("__quill_buffer_new".to_owned()).into())
+    }
+
+    fn client_call(&self) -> Result<&NativeFunc<WasmPtr<RawBuffer>, ()>, PluginError> {
+        self.client_call
+            .get_ref()
+            .ok_or_else(|| ExportError::Missing("__quill_client_call".to_owned()).into())
+    }
+
+    fn buffer(&self, raw: WasmPtr<RawBuffer>) -> Result<Buffer, PluginError> {
+        Ok(Buffer {
+            memory: self.memory()?,
+            reserve: self.buffer_reserve()?,
             raw,
-        }
+        })
     }
 
     fn add_rpc<
@@ -92,54 +232,320 @@ impl<S: Send + Sync + 'static> PluginEnv<S> {
     >(
         &mut self,
         name: &str,
-        callback: fn(&PluginEnv<S>, Args) -> R,
-    ) -> Result<()> {
-        self.rpcs
-            .lock()
-            .map_err(|_| anyhow!("could not lock rpcs"))?
-            .insert(
-                name.to_owned(),
-                Box::new(move |mut buffer: &mut Buffer, env: &PluginEnv<S>| {
-                    let (_, args): (String, Args) =
-                        bincode::deserialize(buffer.as_slice()).unwrap();
-
-                    let result = callback(env, args);
-                    buffer.clear();
-                    bincode::serialize_into(buffer, &result).unwrap();
-                    Ok(())
+        capability: Option<Capability>,
+        callback: fn(&PluginEnv<S>, Args) -> Result<R, PluginError>,
+    ) -> Result<(), PluginError> {
+        lock(&self.rpcs, "rpcs")?.insert(
+            name.to_owned(),
+            Arc::new(HostRpc {
+                capability,
+                call: Box::new(move |buffer: &mut Buffer, env: &PluginEnv<S>| {
+                    let result = bincode::deserialize(buffer.payload()?)
+                        .map_err(PluginError::Decode)
+                        .and_then(|(_, args): (RpcTarget, Args)| callback(env, args));
+                    let result = match result {
+                        Ok(result) => Ok(result),
+                        Err(error) => Err(error.report()?),
+                    };
+                    buffer.reply(&result)
                 }),
-            );
+            }),
+        );
         Ok(())
     }
 
-    fn call<Args: Serialize, R: DeserializeOwned>(&self, name: &str, args: Args) -> Result<R> {
-        // TODO: requires access to buffer.
-        todo!()
+    /// Calls the guest rpc `name`, which is not metered on its own.
+    fn call<Args: Serialize, R: DeserializeOwned>(
+        &self,
+        name: &str,
+        args: &Args,
+    ) -> Result<R, PluginError> {
+        let args = bincode::serialize(args).map_err(PluginError::Encode)?;
+        let result = self.call_raw(name, &args)?;
+        let result: Result<R, RpcError> =
+            bincode::deserialize(&result).map_err(PluginError::Decode)?;
+        result.map_err(PluginError::Rpc)
+    }
+
+    /// Calls the guest rpc `name` with encoded `args`, returning the encoded
+    /// result.
+    fn call_raw(&self, name: &str, args: &[u8]) -> Result<Vec<u8>, PluginError> {
+        if !lock(&self.guest_rpcs, "guest rpcs")?.contains_key(name) {
+            return Err(PluginError::UnknownRpc(name.to_owned()));
+        }
+
+        // Calls can be nested, the outer one is in flight again once the
+        // inner one returned.
+        let outer =
+            lock(&self.guest_rpc_in_flight, "guest rpc in flight")?.replace(name.to_owned());
+        let result = self.profile.time(Site::GuestCall, name, || {
+            self.with_client_buffer(|raw| self.call_with(raw, name, args))
+        });
+        if !matches!(result, Err(PluginError::Trap(_))) {
+            *lock(&self.guest_rpc_in_flight, "guest rpc in flight")? = outer;
+        }
+        result
+    }
+
+    /// Runs `call` with a buffer in guest memory the host may use.
+    fn with_client_buffer<T>(
+        &self,
+        call: impl FnOnce(WasmPtr<RawBuffer>) -> Result<T, PluginError>,
+    ) -> Result<T, PluginError> {
+        // Calls can be nested, e.g. when the guest calls back into the host
+        // while handling one.
+        let raw = match lock(&self.client_buffers, "client buffers")?.pop() {
+            Some(raw) => raw,
+            None => self.buffer_new()?.call()?,
+        };
+        let result = call(raw);
+        lock(&self.client_buffers, "client buffers")?.push(raw);
+        result
+    }
+
+    fn call_with(
+        &self,
+        raw: WasmPtr<RawBuffer>,
+        name: &str,
+        args: &[u8],
+    ) -> Result<Vec<u8>, PluginError> {
+        self.exchange(
+            raw,
+            |buffer| {
+                bincode::serialize_into(&mut *buffer, name).map_err(PluginError::Encode)?;
+                buffer.extend_from_slice(args)
+            },
+            || Ok(self.client_call()?.call(raw)?),
+        )
+    }
+
+    /// Writes a call with the payload `write` writes into the buffer `raw`
+    /// and runs `call`, which has the guest replace it with its reply,
+    /// returning the reply's payload.
+    fn exchange(
+        &self,
+        raw: WasmPtr<RawBuffer>,
+        write: impl FnOnce(&mut Buffer) -> Result<(), PluginError>,
+        call: impl FnOnce() -> Result<(), PluginError>,
+    ) -> Result<Vec<u8>, PluginError> {
+        let call_id = self.call_id.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+        let mut buffer = self.buffer(raw)?;
+        buffer.begin(MessageKind::Call, call_id)?;
+        write(&mut buffer)?;
+        buffer.seal()?;
+        call()?;
+        let buffer = self.buffer(raw)?;
+        let reply =
+            envelope::open_reply(buffer.as_slice()?, call_id).map_err(PluginError::Envelope)?;
+        Ok(reply.to_vec())
+    }
+
+    /// Forwards the call in `buffer` to the rpc `rpc` of the plugin named
+    /// `plugin`, replacing it with the encoded result.
+    fn call_peer(&self, plugin: &str, rpc: &str, buffer: &mut Buffer) -> Result<(), PluginError> {
+        let peer = lock(&self.peers, "peers")?
+            .get(plugin)
+            .cloned()
+            .ok_or_else(|| PluginError::UnknownPlugin(plugin.to_owned()))?;
+
+        // The arguments are passed on as is, only the guests know their type.
+        let mut args = buffer.payload()?;
+        let _: RpcTarget = bincode::deserialize_from(&mut args).map_err(PluginError::Decode)?;
+        let args = args.to_vec();
+
+        let result = peer
+            .metered(CallKind::Call, |env| env.call_raw(rpc, &args))
+            .map_err(|error| match error {
+                PluginError::UnknownRpc(rpc) => {
+                    PluginError::UnknownRpc(format!("{}::{}", plugin, rpc))
+                }
+                error => PluginError::Peer {
+                    plugin: plugin.to_owned(),
+                    error: Box::new(error),
+                },
+            })?;
+        buffer.reply_with(|buffer| buffer.extend_from_slice(&result))
+    }
+}
+
+/// A host rpc and the capability a plugin needs to call it.
+struct HostRpc<S> {
+    capability: Option<Capability>,
+    call: Box<dyn Fn(&mut Buffer, &PluginEnv<S>) -> Result<(), PluginError> + Send + Sync>,
+}
+
+/// Number of rpc names a plugin may resolve to ids.
+const MAX_RESOLVED_RPCS: usize = 4096;
+
+/// Rpcs the guest resolved through `rpc_resolve`.
+struct ResolvedRpcs<S> {
+    /// Indexed by their id.
+    rpcs: Vec<ResolvedRpc<S>>,
+    ids: HashMap<String, RpcId>,
+}
+
+impl<S> Default for ResolvedRpcs<S> {
+    fn default() -> Self {
+        Self {
+            rpcs: Vec::new(),
+            ids: HashMap::new(),
+        }
+    }
+}
+
+/// An rpc name the guest resolved to an id, with the host rpc of that name if
+/// there was one when it was resolved.
+struct ResolvedRpc<S> {
+    name: String,
+    host: Option<Arc<HostRpc<S>>>,
+}
+
+impl<S> Clone for ResolvedRpc<S> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            host: self.host.clone(),
+        }
     }
 }
 
 pub struct Plugin {
-    instance: Instance,
-    env: PluginEnv<World>,
+    name: String,
+    path: PathBuf,
+    modified: SystemTime,
+    manifest: Manifest,
+    config: PluginConfig,
+    /// Set between the plugin being enabled and disabled, while it is ticked.
+    enabled: bool,
+    peer: Peer,
+}
+
+/// State shared by every plugin of a [`PluginManager`].
+#[derive(Clone)]
+pub struct Shared {
+    pub world: Arc<Mutex<World>>,
+    pub layouts: Arc<Mutex<Layouts>>,
+    pub cache: Option<Arc<ModuleCache>>,
+    pub backend: Backend,
+    /// File trap reports are appended to.
+    pub trap_log: Option<PathBuf>,
+    /// Directory holding the data directory of each plugin.
+    pub data_dir: PathBuf,
+    pub peers: Peers,
+    pub watchdog: Arc<Watchdog>,
+    /// Set in deterministic mode.
+    pub deterministic: Option<Arc<Deterministic>>,
+}
+
+/// A plugin read from disk that has not been compiled yet.
+pub struct PluginFile {
+    pub path: PathBuf,
+    pub modified: SystemTime,
+    pub wasm: Vec<u8>,
+    pub manifest: Manifest,
+}
+
+impl PluginFile {
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+        let modified = fs::metadata(&path)?.modified()?;
+        let wasm = fs::read(&path)?;
+        let manifest = Manifest::from_wasm(&wasm)?;
+        Ok(Self {
+            path,
+            modified,
+            wasm,
+            manifest,
+        })
+    }
+}
+
+/// Identifies everything besides the wasm bytes that ends up in a compiled
+/// module, used to key the [`ModuleCache`].
+fn artifact_identity(backend: Backend, config: &PluginConfig) -> String {
+    format!(
+        "{} {} wasmer-{} {} {} fuel-{} pages-{}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        wasmer::VERSION,
+        backend,
+        Target::default().triple(),
+        config.fuel.start,
+        config.max_memory_pages,
+    )
+}
+
+/// Every instruction costs one unit of fuel.
+fn fuel_cost(_: &Operator) -> u64 {
+    1
 }
 
 impl Plugin {
-    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let mut env = PluginEnv::default();
+    pub fn load(file: PluginFile, config: PluginConfig, shared: &Shared) -> Result<Self> {
+        let PluginFile {
+            path,
+            modified,
+            wasm,
+            manifest,
+        } = file;
+        let name = manifest.name.clone();
 
-        let store = Store::new(&JIT::new(LLVM::default()).engine());
+        let mut capabilities = HashSet::new();
+        for &capability in &manifest.capabilities {
+            if config.capabilities.contains(&capability) {
+                capabilities.insert(capability);
+            } else {
+                tracing::warn!(plugin = %name, "capability {} was not granted", capability);
+            }
+        }
+        let mut env = PluginEnv::new(
+            shared.world.clone(),
+            shared.layouts.clone(),
+            shared.peers.clone(),
+            capabilities,
+        );
 
-        let module = Module::from_file(&store, &path)?;
+        // The budget is reset before every call into the guest.
+        let metering = Arc::new(Metering::new(config.fuel.start, fuel_cost));
+        let mut compiler = shared.backend.compiler()?;
+        compiler.push_middleware(metering);
+        let tunables = LimitingTunables::new(
+            BaseTunables::for_target(&Target::default()),
+            Pages(config.max_memory_pages),
+        );
+        let store = Store::new_with_tunables(&JIT::new(compiler).engine(), tunables);
 
-        let mut wasi_env = WasiState::new(
-            path.as_ref()
-                .file_name()
-                .and_then(OsStr::to_str)
-                .unwrap_or("unkown"),
-        )
-        .finalize()?;
+        let module = match &shared.cache {
+            Some(cache) => {
+                let identity = artifact_identity(shared.backend, &config);
+                cache.load(&store, &name, &wasm, &identity)?
+            }
+            None => Module::new(&store, &wasm)?,
+        };
+
+        // The data directory is the only part of the host filesystem the
+        // plugin can see, mounted as its working directory.
+        let data_dir = shared.data_dir.join(&name);
+        fs::create_dir_all(&data_dir)?;
+        let mut wasi_env = WasiState::new(&name)
+            .args(&config.args)
+            .envs(&config.env)
+            .stdout(Box::new(LogOutput::new(&name, Stream::Stdout)))
+            .stderr(Box::new(LogOutput::new(&name, Stream::Stderr)))
+            .preopen(|preopen| {
+                preopen
+                    .directory(&data_dir)
+                    .alias(".")
+                    .read(true)
+                    .write(true)
+                    .create(true)
+            })?
+            .finalize()?;
 
         let mut import_object = wasi_env.import_object(&module)?;
+        if let Some(deterministic) = &shared.deterministic {
+            determinism::override_wasi(&store, &mut import_object, deterministic.clone(), &name);
+        }
         import_object.register(
             "env",
             import_namespace!({
@@ -151,32 +557,80 @@ impl Plugin {
         // // TODO: Return reference to state?
         // env.add_rpc("players", |state, ()| state.clone())?;
 
-        env.add_rpc("world_spawn", |env, entity: quill::ecs::Entity| {
-            let mut world = env.state.lock().unwrap();
-            let mut layouts = env.layouts.lock().unwrap();
-
-            let mut builder = EntityBuilder::new();
-            for (layout, data) in entity.components {
-                builder.add_dynamic(
-                    TypeInfo::of_external(
-                        layouts.external_id(&layout),
-                        Layout::new::<Vec<u8>>(),
-                        |_| (),
-                    ),
-                    data.as_slice(),
-                );
+        env.add_rpc("rpc_register", None, |env, rpcs: Vec<RpcSignature>| {
+            let mut guest_rpcs = lock(&env.guest_rpcs, "guest rpcs")?;
+            for rpc in rpcs {
+                guest_rpcs.insert(rpc.name.clone(), rpc);
             }
-            world.spawn(builder.build());
+            Ok(())
+        })?;
+
+        // Ids are handed out in order and never reused, names that are not an
+        // rpc yet still get one so calling it fails like calling the name.
+        env.add_rpc("rpc_resolve", None, |env, names: Vec<String>| {
+            let rpcs = lock(&env.rpcs, "rpcs")?;
+            let mut resolved = lock(&env.resolved_rpcs, "resolved rpcs")?;
+            let ResolvedRpcs {
+                rpcs: resolved,
+                ids,
+            } = &mut *resolved;
+            names
+                .into_iter()
+                .map(|name| {
+                    if let Some(id) = ids.get(&name) {
+                        return Ok(*id);
+                    }
+                    if resolved.len() >= MAX_RESOLVED_RPCS {
+                        return Err(PluginError::ResolveLimit(MAX_RESOLVED_RPCS));
+                    }
+                    let id = RpcId(resolved.len() as u32);
+                    let host = rpcs.get(&name).cloned();
+                    ids.insert(name.clone(), id);
+                    resolved.push(ResolvedRpc { name, host });
+                    Ok(id)
+                })
+                .collect::<Result<Vec<_>, _>>()
         })?;
 
+        env.add_rpc(
+            "world_spawn",
+            Some(Capability::WorldSpawn),
+            |env, entity: quill::ecs::Entity| {
+                let mut world = lock(&env.state, "world")?;
+                let mut layouts = lock(&env.layouts, "layouts")?;
+
+                let mut builder = EntityBuilder::new();
+                for (layout, data) in entity.components {
+                    env.use_layout(&mut layouts, &layout)?;
+                    builder.add_dynamic(
+                        TypeInfo::of_external(
+                            layouts.external_id(&layout),
+                            Layout::new::<Vec<u8>>(),
+                            |_| (),
+                        ),
+                        data.as_slice(),
+                    );
+                }
+                let entity = world.spawn(builder.build());
+                lock(&env.entities, "entities")?.push(entity);
+                Ok(())
+            },
+        )?;
+
         env.add_rpc(
             "world_query",
+            Some(Capability::WorldQuery),
             // TODO: world should not be the state but union(world, layouts)
             |env, access: quill::ecs::QueryAccess| {
-                let world = env.state.lock().unwrap();
-                let mut layouts = env.layouts.lock().unwrap();
+                let world = lock(&env.state, "world")?;
+                let mut layouts = lock(&env.layouts, "layouts")?;
 
-                let query = access.query(&mut layouts).unwrap();
+                for layout in access.layouts() {
+                    env.use_layout(&mut layouts, layout)?;
+                }
+                let query = access
+                    .query(&mut layouts)
+                    .map_err(|error| PluginError::InvalidArguments(error.to_string()))?;
                 let access = Default::default();
                 let mut query: StatefulQuery<DynamicQuery, DynamicQuery> =
                     StatefulQuery::new(&world, &access, query);
@@ -185,21 +639,300 @@ impl Plugin {
                     entity.immutable;
                     entity.mutable;
                 }
+                Ok(())
             },
         )?;
 
         let instance = Instance::new(&module, &import_object)?;
+        // Only the copies of `env` given to host functions are initialized by
+        // wasmer.
+        env.init_with_instance(&instance)?;
+
+        let peer = Peer {
+            name: name.clone(),
+            interrupt: Arc::new(Interrupt::new(instance.clone(), env.interrupted.clone())),
+            instance,
+            env,
+            fuel: config.fuel,
+            quarantined: Default::default(),
+            traps: Default::default(),
+            max_traps: config.max_traps,
+            depth: Default::default(),
+            // Interrupting calls by wall-clock time is not deterministic.
+            timeout: match shared.deterministic {
+                Some(_) => None,
+                None if config.timeout_ms == 0 => None,
+                None => Some(Duration::from_millis(config.timeout_ms)),
+            },
+            watchdog: shared.watchdog.clone(),
+            trap_log: shared.trap_log.clone(),
+        };
+        let plugin = Plugin {
+            name,
+            path,
+            modified,
+            manifest,
+            config,
+            enabled: false,
+            peer,
+        };
+
+        let start = plugin
+            .peer
+            .instance
+            .exports
+            .get_function("_start")
+            .map_err(PluginError::from)?
+            .clone();
+        let started = plugin
+            .peer
+            .metered(CallKind::Start, |_| Ok(start.call(&[])?));
+        if let Err(error) = started {
+            // `init` might have spawned entities and used layouts already.
+            plugin.unload(EntityPolicy::Despawn)?;
+            return Err(error.into());
+        }
+
+        Ok(plugin)
+    }
+
+    /// Calls the plugin's `__quill_on_enable` export, after which it is
+    /// ticked and other plugins can call its rpcs.
+    pub fn enable(&mut self) -> Result<(), PluginError> {
+        self.lifecycle(CallKind::Enable)?;
+        self.enabled = true;
+        lock(&self.peer.env.peers, "peers")?.insert(self.name.clone(), self.peer.clone());
+        Ok(())
+    }
+
+    /// Calls the plugin's `__quill_on_disable` export, it is not ticked or
+    /// called by other plugins anymore even if the call fails.
+    pub fn disable(&mut self) -> Result<(), PluginError> {
+        self.enabled = false;
+        {
+            let mut peers = lock(&self.peer.env.peers, "peers")?;
+            // A reloaded plugin might already have replaced this instance.
+            let registered = peers.get(&self.name).map_or(false, |peer| {
+                Arc::ptr_eq(&peer.quarantined, &self.peer.quarantined)
+            });
+            if registered {
+                peers.remove(&self.name);
+            }
+        }
+        self.lifecycle(CallKind::Disable)
+    }
 
-        let start = instance.exports.get_function("_start")?;
-        start.call(&[])?;
+    /// Calls the plugin's `__quill_on_tick` export.
+    pub fn tick(&mut self) -> Result<(), PluginError> {
+        self.lifecycle(CallKind::Tick)
+    }
+
+    /// Calls the export of `kind` if the plugin has it.
+    fn lifecycle(&mut self, kind: CallKind) -> Result<(), PluginError> {
+        match self.export::<(), ()>(kind) {
+            Ok(Some(export)) => self.peer.metered(kind, |_| Ok(export.call()?)),
+            Ok(None) => Ok(()),
+            // E.g. an export with the wrong signature, which fails every call.
+            Err(error) => Err(self.peer.failed(kind, error)),
+        }
+    }
+
+    /// Asks the plugin for its state through `__quill_save_state`, `None` if
+    /// it has nothing to save.
+    pub fn save_state(&mut self) -> Result<Option<Vec<u8>>, PluginError> {
+        let export = match self.export::<WasmPtr<RawBuffer>, ()>(CallKind::Save)? {
+            Some(export) => export,
+            None => return Ok(None),
+        };
+        self.peer.metered(CallKind::Save, |env| {
+            env.with_client_buffer(|raw| {
+                let state = env.exchange(raw, |_| Ok(()), || Ok(export.call(raw)?))?;
+                bincode::deserialize(&state).map_err(PluginError::Decode)
+            })
+        })
+    }
+
+    /// Hands `state` saved by a previous instance of the plugin to
+    /// `__quill_load_state`.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), PluginError> {
+        let export = match self.export::<WasmPtr<RawBuffer>, ()>(CallKind::Load)? {
+            Some(export) => export,
+            None => return Ok(()),
+        };
+        self.peer.metered(CallKind::Load, |env| {
+            env.with_client_buffer(|raw| {
+                env.exchange(
+                    raw,
+                    |buffer| buffer.extend_from_slice(state),
+                    || Ok(export.call(raw)?),
+                )?;
+                Ok(())
+            })
+        })
+    }
+
+    /// The export of `kind`, which plugins not built with quill might not
+    /// have.
+    fn export<Args: WasmTypeList, Rets: WasmTypeList>(
+        &self,
+        kind: CallKind,
+    ) -> Result<Option<NativeFunc<Args, Rets>>, PluginError> {
+        match self
+            .peer
+            .instance
+            .exports
+            .get_native_function::<Args, Rets>(kind.export())
+        {
+            Ok(export) => Ok(Some(export)),
+            Err(ExportError::Missing(_)) => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Calls the plugin's rpc `name` with `args`.
+    pub fn call<Args: Serialize, R: DeserializeOwned>(
+        &mut self,
+        name: &str,
+        args: &Args,
+    ) -> Result<R, PluginError> {
+        self.peer
+            .metered(CallKind::Call, |env| env.call(name, args))
+    }
+
+    /// Whether the plugin is not called anymore because it trapped too
+    /// often, see [`PluginManager::reenable`].
+    pub fn is_quarantined(&self) -> bool {
+        self.peer.is_quarantined()
+    }
+
+    /// Number of times the plugin trapped.
+    pub fn traps(&self) -> usize {
+        self.peer.traps()
+    }
+
+    /// Call counts and timings of the plugin since it was loaded or the
+    /// profile was reset.
+    pub fn profile(&self) -> &Profile {
+        &self.peer.env.profile
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 
-        Ok(Plugin { instance, env })
+    pub fn path(&self) -> &Path {
+        &self.path
     }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// The rpcs the plugin announced it handles.
+    pub fn rpcs(&self) -> Result<Vec<RpcSignature>, PluginError> {
+        Ok(lock(&self.peer.env.guest_rpcs, "guest rpcs")?
+            .values()
+            .cloned()
+            .collect())
+    }
+
+    /// Removes the plugin and everything it registered.
+    ///
+    /// The plugin is disabled first if it is enabled. Its instance is
+    /// dropped and its RPCs are removed. Entities spawned by the plugin are
+    /// either despawned, after which the layouts that no other plugin uses
+    /// are forgotten, or handed back to the caller together with the layouts
+    /// their components might use.
+    pub fn unload(mut self, policy: EntityPolicy) -> Result<Orphans> {
+        if self.enabled {
+            // A failure is reported by `metered` and should not keep the
+            // plugin loaded.
+            let _ = self.disable();
+        }
+
+        let Plugin {
+            peer: Peer { instance, env, .. },
+            ..
+        } = self;
+        drop(instance);
+
+        lock(&env.rpcs, "rpcs")?.clear();
+        lock(&env.resolved_rpcs, "resolved rpcs")?.clear();
+
+        let entities = mem::take(&mut *lock(&env.entities, "entities")?);
+        let used_layouts = mem::take(&mut *lock(&env.used_layouts, "used layouts")?);
+        match policy {
+            // The layouts stay in use until the entities are adopted.
+            EntityPolicy::Keep => Ok(Orphans {
+                entities,
+                layouts: used_layouts,
+            }),
+            EntityPolicy::Despawn => {
+                let mut world = lock(&env.state, "world")?;
+                for entity in entities {
+                    // The entity might already have been despawned by someone else.
+                    let _ = world.despawn(entity);
+                }
+
+                let mut layouts = lock(&env.layouts, "layouts")?;
+                for layout in used_layouts {
+                    layouts.release(&layout);
+                }
+                Ok(Orphans::default())
+            }
+        }
+    }
+
+    /// Makes the plugin the owner of `orphans`, e.g. the entities of the
+    /// instance it replaces, and of the layouts they use.
+    pub fn adopt(&self, orphans: Orphans) -> Result<(), PluginError> {
+        let env = &self.peer.env;
+        lock(&env.entities, "entities")?.extend(orphans.entities);
+
+        let mut layouts = lock(&env.layouts, "layouts")?;
+        let mut used = lock(&env.used_layouts, "used layouts")?;
+        for layout in orphans.layouts {
+            // The plugin already holds its own use of the layout.
+            if !used.insert(layout.clone()) {
+                layouts.release(&layout);
+            }
+        }
+        Ok(())
+    }
+
+    /// Modification time of the wasm file at the time it was loaded.
+    pub fn modified(&self) -> SystemTime {
+        self.modified
+    }
+}
+
+/// What to do with the entities of a plugin that is unloaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntityPolicy {
+    Despawn,
+    Keep,
+}
+
+/// Entities of an unloaded plugin that were kept, see [`Plugin::unload`].
+///
+/// The layouts their components might use stay known until another plugin
+/// adopts them, or forever if none does.
+#[derive(Default)]
+pub struct Orphans {
+    pub entities: Vec<Entity>,
+    layouts: HashSet<TypeLayout>,
 }
 
 #[derive(Default)]
 pub struct Layouts {
     layouts: HashMap<quill::ecs::TypeLayout, u64>,
+    /// Number of plugins using each layout.
+    users: HashMap<quill::ecs::TypeLayout, usize>,
+    next: u64,
 }
 
 impl Layouts {
@@ -211,16 +944,35 @@ impl Layouts {
         if let Some(component_id) = self.layouts.get(&layout) {
             *component_id
         } else {
-            let next = self.layouts.len() as u64;
+            // Ids are never reused, forgotten layouts might still have
+            // components in the world.
+            let next = self.next;
+            self.next += 1;
             self.layouts.insert(layout.clone(), next);
             next
         }
     }
+
+    fn acquire(&mut self, layout: &TypeLayout) {
+        *self.users.entry(layout.clone()).or_insert(0) += 1;
+    }
+
+    /// Releases a use of `layout`, forgetting it once no plugin uses it.
+    fn release(&mut self, layout: &TypeLayout) {
+        if let Some(users) = self.users.get_mut(layout) {
+            *users -= 1;
+            if *users == 0 {
+                self.users.remove(layout);
+                self.layouts.remove(layout);
+            }
+        }
+    }
 }
 
 trait IntoBevyAccess {
     fn access(&self, layouts: &mut Layouts) -> Result<QueryAccess>;
     fn component_ids(&self) -> Result<Vec<ComponentId>>;
+    fn layouts(&self) -> Vec<&TypeLayout>;
 
     fn query(&self, layouts: &mut Layouts) -> Result<DynamicQuery>;
 }
@@ -256,6 +1008,24 @@ impl IntoBevyAccess for quill::ecs::QueryAccess {
         todo!()
     }
 
+    fn layouts(&self) -> Vec<&TypeLayout> {
+        use quill::ecs::QueryAccess::*;
+        match self {
+            None => vec![],
+            Read(layout) | Write(layout) => vec![layout],
+            Optional(access) => access.layouts(),
+            With(layout, access) | Without(layout, access) => {
+                let mut layouts = access.layouts();
+                layouts.push(layout);
+                layouts
+            }
+            Union(accesses) => accesses
+                .iter()
+                .flat_map(|access| IntoBevyAccess::layouts(access))
+                .collect(),
+        }
+    }
+
     fn query(&self, layouts: &mut Layouts) -> Result<DynamicQuery> {
         let mut query = DynamicQuery::default();
         query.access = self.access(layouts)?;
@@ -269,7 +1039,7 @@ impl IntoBevyAccess for quill::ecs::QueryAccess {
 struct Buffer<'a> {
     memory: &'a Memory,
     // fn reserve(ptr: WasmPtr<u8, Array>, cap: u32, len: u32, additional: u32)
-    reserve: &'a NativeFunc<(WasmPtr<RawBuffer>, u32)>,
+    reserve: &'a NativeFunc<(WasmPtr<RawBuffer>, u32), u32>,
     raw: WasmPtr<RawBuffer>,
 }
 
@@ -284,32 +1054,44 @@ struct RawBuffer {
 unsafe impl ValueType for RawBuffer {}
 
 impl<'a> Buffer<'a> {
-    fn reserve(&mut self, additional: u32) {
-        let raw = self.raw.deref(self.memory).unwrap().get();
-        if raw.cap < raw.len + additional {
-            self.reserve.call(self.raw, additional).unwrap();
+    fn raw_cell(&self) -> Result<&'a Cell<RawBuffer>, PluginError> {
+        self.raw.deref(self.memory).ok_or(PluginError::OutOfBounds)
+    }
+
+    fn reserve(&mut self, additional: u32) -> Result<(), PluginError> {
+        let raw = self.raw_cell()?.get();
+        if raw.cap < raw.len.saturating_add(additional) {
+            // The guest returns a non-zero status if it could not grow the
+            // buffer, e.g. because it reached its memory limit.
+            if self.reserve.call(self.raw, additional)? != 0 {
+                return Err(PluginError::OutOfMemory {
+                    requested: additional,
+                });
+            }
         }
+        Ok(())
     }
 
-    fn clear(&mut self) {
-        let raw_cell = self.raw.deref(self.memory).unwrap();
+    fn clear(&mut self) -> Result<(), PluginError> {
+        let raw_cell = self.raw_cell()?;
         raw_cell.set(RawBuffer {
             len: 0,
             ..raw_cell.get()
-        })
+        });
+        Ok(())
     }
 
-    fn push(&mut self, byte: u8) {
-        self.extend_from_slice(&[byte]);
+    fn push(&mut self, byte: u8) -> Result<(), PluginError> {
+        self.extend_from_slice(&[byte])
     }
 
-    fn extend_from_slice(&mut self, other: &[u8]) {
-        self.reserve(other.len() as u32);
-        let raw_cell = self.raw.deref(self.memory).unwrap();
+    fn extend_from_slice(&mut self, other: &[u8]) -> Result<(), PluginError> {
+        self.reserve(other.len() as u32)?;
+        let raw_cell = self.raw_cell()?;
         let raw = raw_cell.get();
         raw.ptr
-            .deref(self.memory, raw.len, raw.cap)
-            .unwrap()
+            .deref(self.memory, raw.len, other.len() as u32)
+            .ok_or(PluginError::OutOfBounds)?
             .into_iter()
             .zip(other.iter())
             .for_each(|(cell, value)| cell.set(*value));
@@ -317,34 +1099,93 @@ impl<'a> Buffer<'a> {
             len: raw.len + other.len() as u32,
             ..raw
         });
+        Ok(())
     }
 
-    fn as_slice(&self) -> &[u8] {
-        self
+    fn as_slice(&self) -> Result<&[u8], PluginError> {
+        let raw = self.raw_cell()?.get();
+        let cells = raw
+            .ptr
+            .deref(self.memory, 0, raw.len)
+            .ok_or(PluginError::OutOfBounds)?;
+        Ok(unsafe { mem::transmute(cells) })
+    }
+
+    /// Overwrites the bytes at `offset`, which have to be written already.
+    fn patch(&mut self, offset: u32, bytes: &[u8]) -> Result<(), PluginError> {
+        let raw = self.raw_cell()?.get();
+        if offset.saturating_add(bytes.len() as u32) > raw.len {
+            return Err(PluginError::OutOfBounds);
+        }
+        raw.ptr
+            .deref(self.memory, offset, bytes.len() as u32)
+            .ok_or(PluginError::OutOfBounds)?
+            .iter()
+            .zip(bytes.iter())
+            .for_each(|(cell, value)| cell.set(*value));
+        Ok(())
+    }
+
+    /// The payload of the message in the buffer, which is read in place.
+    fn payload(&self) -> Result<&[u8], PluginError> {
+        Ok(self.as_slice()?.get(HEADER_LEN..).unwrap_or_default())
+    }
+
+    /// Starts a message of `kind` in the buffer, replacing its contents. Its
+    /// payload is written after it and [`Buffer::seal`] finishes it.
+    fn begin(&mut self, kind: MessageKind, call_id: u32) -> Result<(), PluginError> {
+        self.clear()?;
+        self.extend_from_slice(&Header::new(kind, call_id, 0).encode())
+    }
+
+    /// Fills in the payload length of the message started by
+    /// [`Buffer::begin`].
+    fn seal(&mut self) -> Result<(), PluginError> {
+        let len = self.as_slice()?.len().saturating_sub(HEADER_LEN) as u32;
+        self.patch(LEN_OFFSET as u32, &len.to_le_bytes())
+    }
+
+    /// Replaces the call in the buffer with the reply `value`.
+    fn reply<T: Serialize>(&mut self, value: &T) -> Result<(), PluginError> {
+        self.reply_with(|buffer| {
+            bincode::serialize_into(buffer, value).map_err(PluginError::Encode)
+        })
+    }
+
+    /// Replaces the call in the buffer with a reply whose payload `write`
+    /// writes.
+    fn reply_with(
+        &mut self,
+        write: impl FnOnce(&mut Self) -> Result<(), PluginError>,
+    ) -> Result<(), PluginError> {
+        // Echoed even if the rest of the call's envelope is invalid.
+        let call_id = envelope::call_id(self.as_slice()?).unwrap_or_default();
+        self.begin(MessageKind::Reply, call_id)?;
+        write(self)?;
+        self.seal()
     }
 }
 
 impl<'a> Write for Buffer<'a> {
     #[inline]
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.extend_from_slice(buf);
+        self.extend_from_slice(buf).map_err(io_error)?;
         Ok(buf.len())
     }
 
     #[inline]
     fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
         let len = bufs.iter().map(|b| b.len() as u32).sum();
-        self.reserve(len);
+        self.reserve(len).map_err(io_error)?;
         for buf in bufs {
-            self.extend_from_slice(buf);
+            self.extend_from_slice(buf).map_err(io_error)?;
         }
         Ok(len as usize)
     }
 
     #[inline]
     fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
-        self.extend_from_slice(buf);
-        Ok(())
+        self.extend_from_slice(buf).map_err(io_error)
     }
 
     #[inline]
@@ -353,27 +1194,90 @@ impl<'a> Write for Buffer<'a> {
     }
 }
 
-impl<'a> Deref for Buffer<'a> {
-    type Target = [u8];
-    fn deref(&self) -> &Self::Target {
-        let raw = self.raw.deref(self.memory).unwrap().get();
-        unsafe { mem::transmute(raw.ptr.deref(self.memory, 0, raw.len).unwrap()) }
-    }
+fn io_error(error: PluginError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, error)
 }
 
-impl<'a> AsRef<[u8]> for Buffer<'a> {
-    fn as_ref(&self) -> &[u8] {
-        self
+fn __quill_host_call(
+    env: &PluginEnv<World>,
+    buffer_raw: WasmPtr<RawBuffer>,
+) -> Result<(), PluginError> {
+    // Host code does not use fuel, so a call past its deadline is stopped
+    // around it instead.
+    if env.interrupted.load(Ordering::SeqCst) {
+        return Err(PluginError::Interrupted);
     }
-}
+    let mut buffer = env.buffer(buffer_raw)?;
 
-fn __quill_host_call(env: &PluginEnv<World>, buffer_raw: WasmPtr<RawBuffer>) {
-    let mut buffer = env.buffer(buffer_raw);
+    // The rpcs read the payload of the call in place and replace the call
+    // with their reply.
+    let target = envelope::open(buffer.as_slice()?, MessageKind::Call)
+        .map_err(PluginError::Envelope)
+        .and_then(|(_, payload)| {
+            bincode::deserialize::<RpcTarget>(payload).map_err(PluginError::Decode)
+        });
+    let result = target.and_then(|target| {
+        let (name, host) = match target {
+            RpcTarget::Name(name) => (name, None),
+            RpcTarget::Id(id) => {
+                let resolved = lock(&env.resolved_rpcs, "resolved rpcs")?
+                    .rpcs
+                    .get(id.0 as usize)
+                    .cloned()
+                    .ok_or(PluginError::UnknownRpcId(id))?;
+                (resolved.name, resolved.host)
+            }
+        };
+        env.profile.time(Site::HostCall, &name.clone(), || {
+            dispatch_host_call(env, name, host, &mut buffer)
+        })
+    });
+    if env.interrupted.load(Ordering::SeqCst) {
+        return Err(PluginError::Interrupted);
+    }
 
-    let name: String = bincode::deserialize_from(buffer.as_slice()).unwrap();
+    if let Err(error) = result {
+        // Errors the guest caused are reported back to it, others trap it
+        // with the rpc left in flight.
+        buffer.reply(&Err::<(), _>(error.report()?))?;
+    }
+    *lock(&env.rpc_in_flight, "rpc in flight")? = None;
+    Ok(())
+}
 
-    let rpcs = env.rpcs.lock().unwrap();
-    let rpc = rpcs.get(&name).unwrap();
+/// Runs the host rpc or rpc of another plugin `name` with the call in
+/// `buffer`. `host` is the host rpc if it was resolved ahead of the call.
+fn dispatch_host_call(
+    env: &PluginEnv<World>,
+    name: String,
+    host: Option<Arc<HostRpc<World>>>,
+    buffer: &mut Buffer,
+) -> Result<(), PluginError> {
+    // Rpcs of other plugins are named `plugin::rpc`.
+    if let Some(index) = name.find("::") {
+        let (plugin, rpc) = (&name[..index], &name[index + 2..]);
+        *lock(&env.rpc_in_flight, "rpc in flight")? = Some(name.clone());
+        return env.call_peer(plugin, rpc, buffer);
+    }
 
-    rpc(&mut buffer, env).unwrap();
+    let rpc = match host {
+        Some(rpc) => rpc,
+        // Not held during the call, the rpc might resolve other rpcs.
+        None => match lock(&env.rpcs, "rpcs")?.get(&name) {
+            Some(rpc) => rpc.clone(),
+            None => return Err(PluginError::UnknownRpc(name)),
+        },
+    };
+    match rpc.capability {
+        Some(capability) if !env.capabilities.contains(&capability) => {
+            Err(PluginError::PermissionDenied {
+                rpc: name,
+                capability,
+            })
+        }
+        _ => {
+            *lock(&env.rpc_in_flight, "rpc in flight")? = Some(name);
+            (rpc.call)(buffer, env)
+        }
+    }
 }
@@ -0,0 +1,212 @@
+use std::{
+    fmt,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+use serde::{Deserialize, Serialize};
+use wasmer_wasi::{WasiFile, WasiFsError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl fmt::Display for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stream::Stdout => write!(f, "stdout"),
+            Stream::Stderr => write!(f, "stderr"),
+        }
+    }
+}
+
+/// Length in bytes after which a line is emitted even without a newline, so
+/// a plugin cannot make the host buffer its output without end.
+const MAX_LINE: usize = 8 * 1024;
+
+/// WASI stdout or stderr of a plugin that forwards each line it writes as a
+/// tracing event.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogOutput {
+    plugin: String,
+    stream: Stream,
+    lines: Lines,
+}
+
+impl LogOutput {
+    pub fn new(plugin: &str, stream: Stream) -> Self {
+        Self {
+            plugin: plugin.to_owned(),
+            stream,
+            lines: Lines::default(),
+        }
+    }
+
+    fn emit(plugin: &str, stream: Stream, line: &str) {
+        match stream {
+            Stream::Stdout => tracing::info!(plugin = %plugin, stream = %stream, "{}", line),
+            Stream::Stderr => tracing::warn!(plugin = %plugin, stream = %stream, "{}", line),
+        }
+    }
+}
+
+/// Splits output into lines of at most [`MAX_LINE`] bytes.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Lines {
+    /// Output since the last line was emitted.
+    line: Vec<u8>,
+}
+
+impl Lines {
+    /// Adds `buf`, passing every line it completes to `emit`.
+    fn push(&mut self, mut buf: &[u8], mut emit: impl FnMut(&str)) {
+        while !buf.is_empty() {
+            let room = MAX_LINE - self.line.len();
+            // A newline right after a full line still ends it.
+            let window = &buf[..buf.len().min(room + 1)];
+            match window.iter().position(|&b| b == b'\n') {
+                Some(end) => {
+                    self.line.extend_from_slice(&buf[..end]);
+                    buf = &buf[end + 1..];
+                    self.finish(&mut emit);
+                }
+                None if buf.len() > room => {
+                    self.line.extend_from_slice(&buf[..room]);
+                    buf = &buf[room..];
+                    self.finish(&mut emit);
+                }
+                None => {
+                    self.line.extend_from_slice(buf);
+                    buf = &[];
+                }
+            }
+        }
+    }
+
+    /// Passes the line so far to `emit`, without the `\r` of a `\r\n`.
+    fn finish(&mut self, emit: &mut impl FnMut(&str)) {
+        let line = String::from_utf8_lossy(&self.line);
+        emit(line.strip_suffix('\r').unwrap_or(&line));
+        self.line.clear();
+    }
+}
+
+impl Write for LogOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (plugin, stream) = (&self.plugin, self.stream);
+        self.lines
+            .push(buf, |line| Self::emit(plugin, stream, line));
+        Ok(buf.len())
+    }
+
+    // Output is line buffered, a partial line is emitted once it is completed
+    // or the plugin is dropped.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LogOutput {
+    fn drop(&mut self) {
+        if !self.lines.line.is_empty() {
+            let (plugin, stream) = (&self.plugin, self.stream);
+            self.lines
+                .finish(&mut |line| Self::emit(plugin, stream, line));
+        }
+    }
+}
+
+impl Read for LogOutput {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("cannot read from {}", self.stream),
+        ))
+    }
+}
+
+impl Seek for LogOutput {
+    fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("cannot seek {}", self.stream),
+        ))
+    }
+}
+
+#[typetag::serde]
+impl WasiFile for LogOutput {
+    fn last_accessed(&self) -> u64 {
+        0
+    }
+
+    fn last_modified(&self) -> u64 {
+        0
+    }
+
+    fn created_time(&self) -> u64 {
+        0
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn set_len(&mut self, _new_size: u64) -> Result<(), WasiFsError> {
+        Err(WasiFsError::PermissionDenied)
+    }
+
+    fn unlink(&mut self) -> Result<(), WasiFsError> {
+        Ok(())
+    }
+
+    fn bytes_available(&self) -> Result<usize, WasiFsError> {
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The lines emitted for `writes`, and the partial line left.
+    fn split(writes: &[&[u8]]) -> (Vec<String>, Vec<u8>) {
+        let mut lines = Lines::default();
+        let mut emitted = Vec::new();
+        for buf in writes {
+            lines.push(buf, |line| emitted.push(line.to_owned()));
+        }
+        (emitted, lines.line)
+    }
+
+    #[test]
+    fn splits_lines() {
+        let (emitted, rest) = split(&[b"one\ntwo\r\n\nthree"]);
+        assert_eq!(emitted, ["one", "two", ""]);
+        assert_eq!(rest, b"three");
+    }
+
+    #[test]
+    fn completes_partial_lines() {
+        let (emitted, rest) = split(&[b"par", b"tial\r", b"\nnext"]);
+        assert_eq!(emitted, ["partial"]);
+        assert_eq!(rest, b"next");
+    }
+
+    #[test]
+    fn splits_long_lines() {
+        let long = vec![b'a'; MAX_LINE * 2 + 3];
+        let (emitted, rest) = split(&[&long]);
+        assert_eq!(emitted.len(), 2);
+        assert!(emitted.iter().all(|line| line.len() == MAX_LINE));
+        assert_eq!(rest, b"aaa");
+
+        // A newline right after a full line does not add an empty one.
+        let full = vec![b'a'; MAX_LINE];
+        let (emitted, rest) = split(&[&full, b"\nb"]);
+        assert_eq!(emitted.len(), 1);
+        assert_eq!(emitted[0].len(), MAX_LINE);
+        assert_eq!(rest, b"b");
+    }
+}
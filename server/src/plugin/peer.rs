@@ -0,0 +1,150 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use bevy_ecs::World;
+use wasmer::Instance;
+use wasmer_middlewares::metering::{get_remaining_points, set_remaining_points, MeteringPoints};
+
+use super::{
+    lock,
+    watchdog::{Interrupt, Watchdog},
+    CallKind, FuelLimits, PluginEnv, PluginError, Site, TrapReport,
+};
+
+/// Enabled plugins by name, through which plugins call each other.
+pub type Peers = Arc<Mutex<HashMap<String, Peer>>>;
+
+/// The part of a plugin that calls into it, shared with other plugins.
+#[derive(Clone)]
+pub struct Peer {
+    pub(super) name: String,
+    pub(super) instance: Instance,
+    pub(super) env: PluginEnv<World>,
+    pub(super) fuel: FuelLimits,
+    /// Set once the plugin trapped `max_traps` times, counting the calls that
+    /// ran out of fuel, after which it is not called anymore.
+    pub(super) quarantined: Arc<AtomicBool>,
+    pub(super) traps: Arc<AtomicUsize>,
+    pub(super) max_traps: usize,
+    /// Number of calls into the plugin in flight, more than one if plugins
+    /// call each other.
+    pub(super) depth: Arc<AtomicUsize>,
+    /// Wall-clock time a call may take before the watchdog interrupts it.
+    pub(super) timeout: Option<Duration>,
+    pub(super) watchdog: Arc<Watchdog>,
+    pub(super) interrupt: Arc<Interrupt>,
+    pub(super) trap_log: Option<PathBuf>,
+}
+
+impl Peer {
+    /// Runs `call` with the fuel budget of `kind`, quarantining the plugin
+    /// once it trapped too often, running out of fuel being a trap too.
+    /// Traps are reported through a [`TrapReport`].
+    ///
+    /// Calls besides `_start` are interrupted once they take longer than
+    /// the timeout. Nested calls use up the budget and time of the outermost
+    /// call instead of getting their own.
+    pub(super) fn metered<T>(
+        &self,
+        kind: CallKind,
+        call: impl FnOnce(&PluginEnv<World>) -> Result<T, PluginError>,
+    ) -> Result<T, PluginError> {
+        if self.is_quarantined() {
+            return Err(PluginError::Quarantined(self.name.clone()));
+        }
+
+        let mut watch = None;
+        if self.depth.fetch_add(1, Ordering::SeqCst) == 0 {
+            set_remaining_points(&self.instance, self.fuel.limit(kind));
+            if kind != CallKind::Start {
+                watch = self
+                    .timeout
+                    .map(|timeout| self.watchdog.watch(timeout, self.interrupt.clone()));
+            }
+        }
+        let started = Instant::now();
+        let result = call(&self.env);
+        // Rpcs are recorded by the env under their own name.
+        if let Some(site) = Site::of(kind) {
+            self.env
+                .profile
+                .record(site, kind.export(), started.elapsed());
+        }
+        drop(watch);
+        self.depth.fetch_sub(1, Ordering::SeqCst);
+
+        let error = match result {
+            Ok(value) => return Ok(value),
+            Err(PluginError::Trap(error)) => error,
+            Err(error) => return Err(error),
+        };
+        let trace = error.trace().to_vec();
+        let guest_rpc = lock(&self.env.guest_rpc_in_flight, "guest rpc in flight")
+            .ok()
+            .and_then(|mut rpc| rpc.take());
+        let rpc = lock(&self.env.rpc_in_flight, "rpc in flight")
+            .ok()
+            .and_then(|mut rpc| rpc.take());
+        let (message, error) = match get_remaining_points(&self.instance) {
+            // The watchdog took the remaining fuel, or a host function saw the
+            // call was past its deadline.
+            _ if self.interrupt.is_triggered() => {
+                let timeout = self.timeout.unwrap_or_default();
+                let message = PluginError::TimedOut(kind, timeout).to_string();
+                (message, PluginError::TimedOut(kind, timeout))
+            }
+            MeteringPoints::Exhausted => {
+                let message = PluginError::FuelExhausted(kind).to_string();
+                (message, PluginError::FuelExhausted(kind))
+            }
+            // Errors returned by host functions are carried by the trap.
+            MeteringPoints::Remaining(_) => match error.downcast::<PluginError>() {
+                Ok(error) => (error.to_string(), error),
+                Err(error) => (error.message(), PluginError::Trap(error)),
+            },
+        };
+        TrapReport::new(&self.name, kind, guest_rpc, rpc, message, &trace)
+            .emit(self.trap_log.as_deref());
+        self.count_trap();
+        Err(error)
+    }
+
+    /// Logs `error`, which stopped a call of `kind` before it reached the
+    /// plugin and so has no trap report, and counts it like a trap.
+    pub(super) fn failed(&self, kind: CallKind, error: PluginError) -> PluginError {
+        tracing::error!(plugin = %self.name, call = %kind, "call failed: {}", error);
+        self.count_trap();
+        error
+    }
+
+    fn count_trap(&self) {
+        let traps = self.traps.fetch_add(1, Ordering::SeqCst) + 1;
+        if traps >= self.max_traps && !self.is_quarantined() {
+            self.quarantine(&format!("it failed {} times", traps));
+        }
+    }
+
+    fn quarantine(&self, reason: &str) {
+        self.quarantined.store(true, Ordering::SeqCst);
+        tracing::warn!(
+            plugin = %self.name,
+            "plugin is quarantined because {}, reload or re-enable it once fixed",
+            reason
+        );
+    }
+
+    pub(super) fn is_quarantined(&self) -> bool {
+        self.quarantined.load(Ordering::SeqCst)
+    }
+
+    pub(super) fn traps(&self) -> usize {
+        self.traps.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,249 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::CallKind;
+
+/// Number of histogram buckets, the last one holds every call taking longer
+/// than about 18 minutes.
+const BUCKETS: usize = 31;
+
+/// Where a plugin spent its time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Site {
+    /// An rpc the plugin called through `__quill_host_call`, either of the
+    /// host or of another plugin.
+    HostCall,
+    /// An rpc of the plugin called by the host or another plugin.
+    GuestCall,
+    /// The plugin's tick, which runs its systems.
+    System,
+    /// One of the other exports the host calls, e.g. `__quill_on_enable`.
+    Lifecycle,
+}
+
+impl Site {
+    /// The site of a call of `kind`, `None` for rpcs which are recorded under
+    /// their own name.
+    pub fn of(kind: CallKind) -> Option<Self> {
+        match kind {
+            CallKind::Call => None,
+            CallKind::Tick => Some(Site::System),
+            _ => Some(Site::Lifecycle),
+        }
+    }
+}
+
+impl fmt::Display for Site {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Site::HostCall => "host call",
+            Site::GuestCall => "guest call",
+            Site::System => "system",
+            Site::Lifecycle => "lifecycle",
+        })
+    }
+}
+
+/// Call counts and timings of one plugin by site and name.
+///
+/// The time of a call includes the calls it makes, e.g. a guest rpc includes
+/// the host rpcs it calls.
+#[derive(Default)]
+pub struct Profile {
+    calls: Mutex<BTreeMap<(Site, String), Histogram>>,
+}
+
+impl Profile {
+    pub fn time<T>(&self, site: Site, name: &str, call: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = call();
+        self.record(site, name, started.elapsed());
+        result
+    }
+
+    pub fn record(&self, site: Site, name: &str, elapsed: Duration) {
+        // A poisoned lock only loses samples.
+        if let Ok(mut calls) = self.calls.lock() {
+            calls
+                .entry((site, name.to_owned()))
+                .or_default()
+                .record(elapsed);
+        }
+    }
+
+    /// The calls recorded since the last reset.
+    pub fn snapshot(&self) -> BTreeMap<(Site, String), Histogram> {
+        self.calls
+            .lock()
+            .map(|calls| calls.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn reset(&self) {
+        if let Ok(mut calls) = self.calls.lock() {
+            calls.clear();
+        }
+    }
+}
+
+/// Durations of calls, bucketed by powers of two microseconds.
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    count: u64,
+    total: Duration,
+    max: Duration,
+    /// Bucket `i` counts the calls taking less than `2^i` microseconds and at
+    /// least `2^(i - 1)`.
+    buckets: [u64; BUCKETS],
+}
+
+impl Histogram {
+    pub fn record(&mut self, elapsed: Duration) {
+        let micros = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = (64 - micros.leading_zeros() as usize).min(BUCKETS - 1);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total += elapsed;
+        self.max = self.max.max(elapsed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::default(),
+            count => Duration::from_nanos((self.total.as_nanos() / count as u128) as u64),
+        }
+    }
+
+    /// Upper bound of the duration of the fraction `quantile` of the calls
+    /// that took the least time, e.g. `0.99` for the 99th percentile.
+    pub fn quantile(&self, quantile: f64) -> Duration {
+        let rank = (self.count as f64 * quantile).ceil() as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank.max(1) {
+                return Duration::from_micros(1 << bucket).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+/// The profiles of every loaded plugin, printed as a table per plugin with
+/// the plugins that took the most time first.
+#[derive(Debug, Clone, Default)]
+pub struct ProfileReport {
+    pub plugins: BTreeMap<String, BTreeMap<(Site, String), Histogram>>,
+}
+
+impl ProfileReport {
+    /// Time spent in the plugin named `plugin`, not counting host calls as
+    /// they are part of the guest calls making them.
+    pub fn total(&self, plugin: &str) -> Duration {
+        self.plugins
+            .get(plugin)
+            .map_or_else(Duration::default, |calls| {
+                calls
+                    .iter()
+                    .filter(|((site, _), _)| *site != Site::HostCall)
+                    .map(|(_, histogram)| histogram.total())
+                    .sum()
+            })
+    }
+}
+
+impl fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut plugins: Vec<_> = self.plugins.iter().collect();
+        plugins.sort_by_key(|(name, _)| std::cmp::Reverse(self.total(name)));
+
+        for (name, calls) in plugins {
+            writeln!(f, "{} ({:?})", name, self.total(name))?;
+            writeln!(
+                f,
+                "  {:<10} {:<32} {:>8} {:>12} {:>12} {:>12} {:>12} {:>12}",
+                "site", "name", "calls", "total", "mean", "p50", "p99", "max"
+            )?;
+            let mut calls: Vec<_> = calls.iter().collect();
+            calls.sort_by_key(|(_, histogram)| std::cmp::Reverse(histogram.total()));
+            for ((site, name), histogram) in calls {
+                writeln!(
+                    f,
+                    "  {:<10} {:<32} {:>8} {:>12} {:>12} {:>12} {:>12} {:>12}",
+                    site.to_string(),
+                    name,
+                    histogram.count(),
+                    format!("{:?}", histogram.total()),
+                    format!("{:?}", histogram.mean()),
+                    format!("{:?}", histogram.quantile(0.5)),
+                    format!("{:?}", histogram.quantile(0.99)),
+                    format!("{:?}", histogram.max()),
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recorded(micros: &[u64]) -> Histogram {
+        let mut histogram = Histogram::default();
+        for &micros in micros {
+            histogram.record(Duration::from_micros(micros));
+        }
+        histogram
+    }
+
+    #[test]
+    fn buckets_by_power_of_two() {
+        let histogram = recorded(&[0, 1, 2, 3, 4, 7, 8]);
+        assert_eq!(histogram.buckets[..5], [1, 1, 2, 2, 1]);
+        assert_eq!(histogram.buckets[5..].iter().sum::<u64>(), 0);
+    }
+
+    #[test]
+    fn last_bucket_holds_long_calls() {
+        let histogram = recorded(&[(1 << 29) - 1, 1 << 29, 1 << 40, u64::MAX]);
+        assert_eq!(histogram.buckets[BUCKETS - 2], 1);
+        assert_eq!(histogram.buckets[BUCKETS - 1], 3);
+    }
+
+    #[test]
+    fn quantile_is_bucket_bound() {
+        let histogram = recorded(&[3; 10]);
+        assert_eq!(histogram.quantile(0.5), Duration::from_micros(3));
+
+        let mut micros = vec![3; 10];
+        micros.push(1000);
+        let histogram = recorded(&micros);
+        assert_eq!(histogram.quantile(0.5), Duration::from_micros(4));
+        assert_eq!(histogram.quantile(0.9), Duration::from_micros(4));
+        // Capped by the longest call rather than the bound of its bucket.
+        assert_eq!(histogram.quantile(0.99), Duration::from_micros(1000));
+        assert_eq!(histogram.quantile(1.0), Duration::from_micros(1000));
+    }
+
+    #[test]
+    fn quantile_of_no_calls() {
+        assert_eq!(Histogram::default().quantile(0.5), Duration::default());
+    }
+}
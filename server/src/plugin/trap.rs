@@ -0,0 +1,202 @@
+use std::{fmt, fs::OpenOptions, io::Write, path::Path};
+
+use wasmer::FrameInfo;
+
+use super::CallKind;
+
+/// Describes a trap of a plugin, with repeated frames of its trace collapsed.
+#[derive(Debug, Clone)]
+pub struct TrapReport {
+    pub plugin: String,
+    pub call: CallKind,
+    /// The rpc of the plugin that was called, for calls of [`CallKind::Call`].
+    pub guest_rpc: Option<String>,
+    /// The host rpc that was being handled when the plugin trapped.
+    pub rpc: Option<String>,
+    pub message: String,
+    pub frames: Vec<Frame>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub module: String,
+    pub func_index: u32,
+    /// Demangled function name without its hash, if the module has a name
+    /// section.
+    pub function: Option<String>,
+    /// Number of times the frame occurs in a row, e.g. due to recursion.
+    pub count: usize,
+}
+
+impl TrapReport {
+    pub fn new(
+        plugin: &str,
+        call: CallKind,
+        guest_rpc: Option<String>,
+        rpc: Option<String>,
+        message: String,
+        trace: &[FrameInfo],
+    ) -> Self {
+        Self {
+            plugin: plugin.to_owned(),
+            call,
+            guest_rpc,
+            rpc,
+            message,
+            frames: collapse(trace),
+        }
+    }
+
+    /// Logs the report and appends it to `file` if set.
+    pub fn emit(&self, file: Option<&Path>) {
+        tracing::error!(
+            plugin = %self.plugin,
+            call = %self.call,
+            guest_rpc = ?self.guest_rpc,
+            rpc = ?self.rpc,
+            "{}",
+            self
+        );
+
+        if let Some(path) = file {
+            let result = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", self));
+            if let Err(error) = result {
+                tracing::warn!(
+                    "could not write trap report to {}: {}",
+                    path.display(),
+                    error
+                );
+            }
+        }
+    }
+}
+
+impl fmt::Display for TrapReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.guest_rpc {
+            Some(rpc) => write!(f, "plugin {} trapped in its rpc {}", self.plugin, rpc)?,
+            None => write!(f, "plugin {} trapped during {}", self.plugin, self.call)?,
+        }
+        if let Some(rpc) = &self.rpc {
+            write!(f, " handling host rpc {}", rpc)?;
+        }
+        writeln!(f, ": {}", self.message)?;
+        for frame in &self.frames {
+            match &frame.function {
+                Some(function) => write!(f, "    at {}", function)?,
+                None => write!(f, "    at <unnamed>")?,
+            }
+            write!(f, " ({}[{}])", frame.module, frame.func_index)?;
+            if frame.count > 1 {
+                write!(f, " x{}", frame.count)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+fn collapse(trace: &[FrameInfo]) -> Vec<Frame> {
+    collapse_frames(trace.iter().map(|info| Frame {
+        module: info.module_name().to_owned(),
+        func_index: info.func_index(),
+        function: info.function_name().map(symbolicate),
+        count: 1,
+    }))
+}
+
+/// Merges frames of the same function that follow each other into one.
+fn collapse_frames(trace: impl IntoIterator<Item = Frame>) -> Vec<Frame> {
+    let mut frames: Vec<Frame> = Vec::new();
+    for frame in trace {
+        match frames.last_mut() {
+            Some(last) if last.module == frame.module && last.func_index == frame.func_index => {
+                last.count += frame.count
+            }
+            _ => frames.push(frame),
+        }
+    }
+    frames
+}
+
+/// Demangles `name` and strips the trailing `::h<hash>` rustc adds to
+/// symbols.
+fn symbolicate(name: &str) -> String {
+    let name = format!("{:#}", rustc_demangle::demangle(name));
+    match name.rfind("::h") {
+        Some(index)
+            if name.len() - index == 19
+                && name[index + 3..].bytes().all(|b| b.is_ascii_hexdigit()) =>
+        {
+            name[..index].to_owned()
+        }
+        _ => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(module: &str, func_index: u32) -> Frame {
+        Frame {
+            module: module.to_owned(),
+            func_index,
+            function: None,
+            count: 1,
+        }
+    }
+
+    #[test]
+    fn collapses_repeated_frames() {
+        let trace = vec![
+            frame("a", 1),
+            frame("a", 1),
+            frame("a", 1),
+            frame("a", 2),
+            frame("b", 2),
+            frame("a", 1),
+        ];
+        let frames = collapse_frames(trace);
+        let counts: Vec<_> = frames
+            .iter()
+            .map(|frame| (frame.module.as_str(), frame.func_index, frame.count))
+            .collect();
+        assert_eq!(counts, [("a", 1, 3), ("a", 2, 1), ("b", 2, 1), ("a", 1, 1)]);
+    }
+
+    #[test]
+    fn strips_hash() {
+        assert_eq!(
+            symbolicate("_ZN4core9panicking5panic17h0123456789abcdefE"),
+            "core::panicking::panic"
+        );
+        assert_eq!(
+            symbolicate("plugin::on_tick::h0123456789abcdef"),
+            "plugin::on_tick"
+        );
+    }
+
+    #[test]
+    fn keeps_names_without_hash() {
+        assert_eq!(symbolicate("plugin::on_tick"), "plugin::on_tick");
+        // Too short or too long for a hash.
+        assert_eq!(
+            symbolicate("plugin::h0123456789abcde"),
+            "plugin::h0123456789abcde"
+        );
+        assert_eq!(
+            symbolicate("plugin::h0123456789abcdef0"),
+            "plugin::h0123456789abcdef0"
+        );
+        // Not hexadecimal.
+        assert_eq!(
+            symbolicate("plugin::hello_world_tick"),
+            "plugin::hello_world_tick"
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use wasmer::Instance;
+use wasmer_middlewares::metering::set_remaining_points;
+
+/// How long the watchdog sleeps when no call is watched.
+const IDLE: Duration = Duration::from_millis(100);
+/// How often a call that is past its deadline is interrupted again, as the
+/// guest might write back the fuel it had left before the interrupt.
+const RETRIGGER: Duration = Duration::from_millis(1);
+
+/// Interrupts calls into plugins that run past their deadline, from a thread
+/// of its own.
+///
+/// Only guest code is interrupted. Host code the guest is blocked on, e.g. a
+/// slow host rpc or a WASI call like `poll_oneoff` or a blocking read, runs
+/// to completion, after which `__quill_host_call` traps the call. WASI calls
+/// trap once the guest runs again.
+pub struct Watchdog {
+    calls: Mutex<Calls>,
+    wakeup: Condvar,
+}
+
+#[derive(Default)]
+struct Calls {
+    next: u64,
+    deadlines: HashMap<u64, (Instant, Arc<Interrupt>)>,
+}
+
+/// Interrupts an instance by taking away its fuel, making it trap the next
+/// time the metering middleware checks it.
+pub struct Interrupt {
+    instance: Instance,
+    /// Shared with host functions, which check it as they do not use fuel.
+    triggered: Arc<AtomicBool>,
+}
+
+/// A watched call, which stops being watched and interrupted once this is
+/// dropped.
+pub struct Watch<'a> {
+    watchdog: &'a Watchdog,
+    id: u64,
+}
+
+impl Watchdog {
+    pub fn spawn() -> Arc<Self> {
+        let watchdog = Arc::new(Self {
+            calls: Mutex::new(Calls::default()),
+            wakeup: Condvar::new(),
+        });
+        let weak = Arc::downgrade(&watchdog);
+        thread::Builder::new()
+            .name("plugin watchdog".to_owned())
+            .spawn(move || run(weak))
+            .expect("could not spawn the plugin watchdog");
+        watchdog
+    }
+
+    /// Interrupts `interrupt` if the returned [`Watch`] is not dropped
+    /// within `timeout`.
+    pub fn watch(&self, timeout: Duration, interrupt: Arc<Interrupt>) -> Watch<'_> {
+        interrupt.triggered.store(false, Ordering::SeqCst);
+        // A poisoned lock means the watchdog thread died and nothing will be
+        // interrupted.
+        let id = match self.calls.lock() {
+            Ok(mut calls) => {
+                let id = calls.next;
+                calls.next += 1;
+                calls
+                    .deadlines
+                    .insert(id, (Instant::now() + timeout, interrupt));
+                id
+            }
+            Err(_) => u64::MAX,
+        };
+        self.wakeup.notify_one();
+        Watch { watchdog: self, id }
+    }
+}
+
+impl Drop for Watch<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut calls) = self.watchdog.calls.lock() {
+            calls.deadlines.remove(&self.id);
+        }
+    }
+}
+
+impl Interrupt {
+    pub fn new(instance: Instance, triggered: Arc<AtomicBool>) -> Self {
+        Self {
+            instance,
+            triggered,
+        }
+    }
+
+    /// Whether the watched call was interrupted.
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+
+    fn trigger(&self) {
+        self.triggered.store(true, Ordering::SeqCst);
+        set_remaining_points(&self.instance, 0);
+    }
+}
+
+/// Runs until the watchdog is dropped.
+fn run(watchdog: Weak<Watchdog>) {
+    while let Some(watchdog) = watchdog.upgrade() {
+        let mut calls = match watchdog.calls.lock() {
+            Ok(calls) => calls,
+            Err(_) => return,
+        };
+
+        // Interrupted calls stay watched until their `Watch` is dropped.
+        let now = Instant::now();
+        for (deadline, interrupt) in calls.deadlines.values() {
+            if *deadline <= now {
+                interrupt.trigger();
+            }
+        }
+
+        let wait = calls
+            .deadlines
+            .values()
+            .map(|(deadline, _)| match deadline.checked_duration_since(now) {
+                Some(wait) if wait > Duration::default() => wait,
+                _ => RETRIGGER,
+            })
+            .min()
+            .unwrap_or(IDLE);
+        if watchdog.wakeup.wait_timeout(calls, wait).is_err() {
+            return;
+        }
+    }
+}